@@ -0,0 +1,35 @@
+#![feature(test)]
+
+extern crate test;
+
+use lang::{env::Env, eval::eval};
+use test::Bencher;
+
+// Same workload as `examples/fib.lisp`, with a smaller `n`.
+const FIB: &str = r#"
+(define (fib n) (cond
+  ((= n 0) 0)
+  ((= n 1) 1)
+  (else (+ (fib (- n 1)) (fib (- n 2))))
+))
+"#;
+
+fn bench_fib(b: &mut Bencher, n: usize) {
+    let mut env = Env::default();
+    for expr in lang::eval(FIB).unwrap() {
+        eval(&expr, &mut env).unwrap();
+    }
+    let call = lang::eval(&format!("(fib {n})")).unwrap();
+
+    b.iter(|| eval(&call[0], &mut env).unwrap());
+}
+
+#[bench]
+fn fib_10(b: &mut Bencher) {
+    bench_fib(b, 10);
+}
+
+#[bench]
+fn fib_20(b: &mut Bencher) {
+    bench_fib(b, 20);
+}
@@ -1,25 +1,45 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::mem;
 use std::rc::Rc;
 
-use crate::{LangError, LangParser, LangResult, Rule};
-use pest::iterators::Pair;
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    env::{Env, Frame},
+    macros::Macro,
+    number::Number,
+    record::{Record, RecordProc},
+    symbol::Symbol,
+    LangError, LangParser, LangResult, Rule,
+};
+use pest::iterators;
 use pest::Parser;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum Expr {
     Nil,
-    Num(f64),
+    Num(Number),
     String(Rc<str>),
+    Char(char),
     Bool(bool),
-    QExpr(Box<Expr>),
-    SExpr(Vec<Expr>),
-    //
-    // FIXME:
-    // The Symbol doesn't need mutablity.
-    Symbol(String),
+    SExpr(Rc<[Expr]>),
+    Pair(Rc<Pair>),
+    Vector(Rc<RefCell<Vec<Expr>>>),
+    HashTable(Rc<RefCell<HashMap<Expr, Expr>>>),
+    // Immutable, updating one gives a new map or set.
+    // They keep the insertion order, so they print the way they were written.
+    Map(Rc<IndexMap<Expr, Expr>>),
+    Set(Rc<IndexSet<Expr>>),
+    Record(Rc<Record>),
+    Symbol(Symbol),
 
     Fn(fn(&[Expr]) -> LangResult<Expr>),
     Lambda(Lambda),
+    Macro(Rc<Macro>),
+    RecordProc(Rc<RecordProc>),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
@@ -27,20 +47,187 @@ pub enum ExprType {
     Nil,
     Num,
     String,
+    Char,
     Bool,
-    QExpr,
     SExpr,
+    Pair,
+    Vector,
+    HashTable,
+    Map,
+    Set,
+    Record,
     Symbol,
     Fn,
     Lambda,
+    Macro,
+    RecordProc,
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub struct Lambda {
-    // FIXME:
-    // Rc?
-    pub params: Rc<Expr>,
-    pub body: Rc<Expr>,
+    // Parsed once when the lambda is created, not on every call.
+    pub params: Rc<[Symbol]>,
+    // Bound to a list of the arguments after `params`, like `args` in `(lambda (x . args) ...)`.
+    pub rest: Option<Symbol>,
+    pub body: Rc<[Expr]>,
+    // The environment where the lambda was defined, so free variables
+    // in the body are resolved lexically instead of at the call site.
+    pub env: Env,
+    // The loop of a named let binds itself to its name in every call,
+    // storing it in `env` would make the scope own itself.
+    pub name: Option<Symbol>,
+}
+
+impl Lambda {
+    // The scope of a call, a child of the captured environment.
+    pub fn call_env(&self, mut args: Vec<Expr>) -> LangResult<Env> {
+        let rest = match self.rest {
+            Some(rest) if args.len() >= self.params.len() => Some((
+                rest,
+                Expr::list(args.split_off(self.params.len()), Expr::Nil),
+            )),
+            None if args.len() == self.params.len() => None,
+            _ => return Err(LangError::InvalidArgsLen),
+        };
+
+        let mut frame: Frame = self.params.iter().cloned().zip(args).collect();
+        frame.extend(rest);
+        if let Some(name) = self.name {
+            frame.push((name, Expr::Lambda(self.clone())));
+        }
+        Ok(self.env.extend(frame))
+    }
+}
+
+// A mutable cons cell.
+// Lists are chains of pairs terminated by `Expr::Nil`,
+// any other terminator makes an improper list like `(1 2 . 3)`.
+#[derive(Debug)]
+pub struct Pair {
+    pub car: RefCell<Expr>,
+    pub cdr: RefCell<Expr>,
+}
+
+// The default drop frees the `cdr` chain recursively,
+// which overflows the stack for a long list,
+// so the pairs only owned by this one are unlinked in a loop instead.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut next = self.cdr.replace(Expr::Nil);
+        while let Expr::Pair(pair) = next {
+            match Rc::try_unwrap(pair) {
+                Ok(pair) => next = pair.cdr.replace(Expr::Nil),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// Like the drop, walks the `cdr` chains in a loop,
+// only the `car`s are compared recursively.
+impl PartialEq for Pair {
+    fn eq(&self, other: &Self) -> bool {
+        if *self.car.borrow() != *other.car.borrow() {
+            return false;
+        }
+        let mut x = self.cdr.borrow().clone();
+        let mut y = other.cdr.borrow().clone();
+        loop {
+            let (Expr::Pair(p), Expr::Pair(q)) = (&x, &y) else {
+                return x == y;
+            };
+            if Rc::ptr_eq(p, q) {
+                return true;
+            }
+            if *p.car.borrow() != *q.car.borrow() {
+                return false;
+            }
+            let next = (p.cdr.borrow().clone(), q.cdr.borrow().clone());
+            (x, y) = next;
+        }
+    }
+}
+
+// The equality of `equal?`, which is also the one of hash table keys:
+// lists, pairs and vectors are equal when their items are,
+// numbers are compared with `Number::eqv`, so `1` and `1.0` are different keys,
+// maps and sets are equal when they have the same entries in any order,
+// and procedures, macros, hash tables and records are only equal to themselves.
+// Comparing a cyclic list or vector with an equal one never ends.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Expr::Nil, Expr::Nil) => true,
+            (Expr::Num(x), Expr::Num(y)) => x.eqv(y),
+            (Expr::String(x), Expr::String(y)) => x == y,
+            (Expr::Char(x), Expr::Char(y)) => x == y,
+            (Expr::Bool(x), Expr::Bool(y)) => x == y,
+            (Expr::SExpr(x), Expr::SExpr(y)) => x == y,
+            (Expr::Pair(x), Expr::Pair(y)) => x == y,
+            (Expr::Vector(x), Expr::Vector(y)) => x == y,
+            (Expr::HashTable(x), Expr::HashTable(y)) => Rc::ptr_eq(x, y),
+            (Expr::Map(x), Expr::Map(y)) => x == y,
+            (Expr::Set(x), Expr::Set(y)) => x == y,
+            (Expr::Record(x), Expr::Record(y)) => Rc::ptr_eq(x, y),
+            (Expr::Symbol(x), Expr::Symbol(y)) => x == y,
+            (Expr::Fn(x), Expr::Fn(y)) => std::ptr::fn_addr_eq(*x, *y),
+            (Expr::Lambda(x), Expr::Lambda(y)) => Rc::ptr_eq(&x.body, &y.body) && x.env == y.env,
+            (Expr::Macro(x), Expr::Macro(y)) => Rc::ptr_eq(x, y),
+            (Expr::RecordProc(x), Expr::RecordProc(y)) => Rc::ptr_eq(x, y),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Expr {}
+
+// Consistent with `PartialEq`, equal values hash the same.
+// Mutating a list or vector used as a key changes its hash,
+// so the key can't be found anymore.
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Expr::Nil => {}
+            Expr::Num(n) => n.hash_eqv(state),
+            Expr::String(s) => s.hash(state),
+            Expr::Char(c) => c.hash(state),
+            Expr::Bool(b) => b.hash(state),
+            Expr::SExpr(s_expr) => s_expr.hash(state),
+            // Hashes the `car`s in a loop, and the tail which ends the list.
+            Expr::Pair(pair) => {
+                let mut pair = pair.clone();
+                loop {
+                    pair.car.borrow().hash(state);
+                    let cdr = pair.cdr.borrow().clone();
+                    match cdr {
+                        Expr::Pair(next) => pair = next,
+                        tail => break tail.hash(state),
+                    }
+                }
+            }
+            Expr::Vector(vector) => vector.borrow().hash(state),
+            Expr::HashTable(table) => Rc::as_ptr(table).hash(state),
+            Expr::Map(map) => hash_unordered(map.iter(), state),
+            Expr::Set(set) => hash_unordered(set.iter(), state),
+            Expr::Record(record) => Rc::as_ptr(record).hash(state),
+            Expr::Symbol(s) => s.hash(state),
+            Expr::Fn(f) => (*f as usize).hash(state),
+            Expr::Lambda(lambda) => Rc::as_ptr(&lambda.body).hash(state),
+            Expr::Macro(m) => Rc::as_ptr(m).hash(state),
+            Expr::RecordProc(proc) => Rc::as_ptr(proc).hash(state),
+        }
+    }
+}
+
+// Sums the hashes of the items, so the order of the items doesn't matter.
+fn hash_unordered<T: Hash, H: Hasher>(items: impl Iterator<Item = T>, state: &mut H) {
+    let sum = items.fold(0u64, |sum, item| {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        sum.wrapping_add(hasher.finish())
+    });
+    sum.hash(state);
 }
 
 macro_rules! to {
@@ -55,58 +242,440 @@ macro_rules! to {
 }
 
 impl Expr {
-    to!(inner_num => Num(n) => f64);
+    to!(inner_num => Num(n) => Number);
     to!(inner_string => String(s) => Rc<str>);
+    to!(inner_char => Char(c) => char);
     to!(inner_bool => Bool(b) => bool);
-    to!(inner_q_expr => QExpr(q_expr) => Box<Expr>);
-    to!(inner_s_expr => SExpr(s_expr) => Vec<Expr>);
-    to!(inner_symbol => Symbol(s) => String);
+    to!(inner_s_expr => SExpr(s_expr) => Rc<[Expr]>);
+    to!(inner_symbol => Symbol(s) => Symbol);
+    to!(inner_pair => Pair(pair) => Rc<Pair>);
+    to!(inner_vector => Vector(vector) => Rc<RefCell<Vec<Expr>>>);
+    to!(inner_hash_table => HashTable(table) => Rc<RefCell<HashMap<Expr, Expr>>>);
+    to!(inner_map => Map(map) => Rc<IndexMap<Expr, Expr>>);
+    to!(inner_set => Set(set) => Rc<IndexSet<Expr>>);
+
+    // Follows Scheme: `#f` is the only false value,
+    // everything else including `nil`, `0` and `""` counts as true.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Expr::Bool(false))
+    }
+
+    // The equality of `eq?`, whether both are the same object.
+    // Like `eqv?`, except big integers and ratios are only the same as themselves,
+    // the arithmetic allocates a new one for every result.
+    pub fn is_eq(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::Num(Number::Big(x)), Expr::Num(Number::Big(y))) => Rc::ptr_eq(x, y),
+            (Expr::Num(Number::Ratio(x)), Expr::Num(Number::Ratio(y))) => Rc::ptr_eq(x, y),
+            _ => self.is_eqv(other),
+        }
+    }
+
+    // The equality of `eqv?`.
+    // Strings, lists, vectors, maps and sets are the same only when they are
+    // the same object, even with equal contents, unlike `equal?`.
+    // A new container type should be compared the same way here,
+    // and by its contents in the `PartialEq` of `Expr`, unless it is mutable
+    // and identified by its identity like hash tables and records.
+    // Everything else is compared like `equal?`: numbers with `Number::eqv`,
+    // so `(eqv? 1 1.0)` is false, characters, booleans and symbols by value,
+    // and procedures, macros, hash tables and records by identity.
+    pub fn is_eqv(&self, other: &Expr) -> bool {
+        match (self, other) {
+            (Expr::String(x), Expr::String(y)) => Rc::ptr_eq(x, y),
+            (Expr::SExpr(x), Expr::SExpr(y)) => Rc::ptr_eq(x, y),
+            (Expr::Pair(x), Expr::Pair(y)) => Rc::ptr_eq(x, y),
+            (Expr::Vector(x), Expr::Vector(y)) => Rc::ptr_eq(x, y),
+            (Expr::Map(x), Expr::Map(y)) => Rc::ptr_eq(x, y),
+            (Expr::Set(x), Expr::Set(y)) => Rc::ptr_eq(x, y),
+            _ => self == other,
+        }
+    }
+
+    pub fn cons(car: Expr, cdr: Expr) -> Expr {
+        let car = RefCell::new(car);
+        let cdr = RefCell::new(cdr);
+        Expr::Pair(Rc::new(Pair { car, cdr }))
+    }
+
+    // Builds a proper list from `items`, with an optional improper `tail`.
+    pub fn list<I>(items: I, tail: Expr) -> Expr
+    where
+        I: IntoIterator<Item = Expr>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        items
+            .into_iter()
+            .rev()
+            .fold(tail, |cdr, car| Expr::cons(car, cdr))
+    }
+
+    pub fn vector(items: Vec<Expr>) -> Expr {
+        Expr::Vector(Rc::new(RefCell::new(items)))
+    }
+
+    // Pairs up `items` as `key value ...`, a later key replaces an earlier one.
+    pub fn map(items: Vec<Expr>) -> LangResult<Expr> {
+        let entries = items.chunks_exact(2);
+        if let [key] = entries.remainder() {
+            return Err(LangError::Other(format!(
+                "expected a value for the key {} of a map",
+                Written(key)
+            )));
+        }
+        let map = entries
+            .map(|entry| (entry[0].clone(), entry[1].clone()))
+            .collect();
+        Ok(Expr::Map(Rc::new(map)))
+    }
+
+    pub fn set(items: Vec<Expr>) -> Expr {
+        Expr::Set(Rc::new(items.into_iter().collect()))
+    }
+
+    pub fn car(&self) -> LangResult<Expr> {
+        Ok(self.inner_pair()?.car.borrow().clone())
+    }
+
+    pub fn cdr(&self) -> LangResult<Expr> {
+        Ok(self.inner_pair()?.cdr.borrow().clone())
+    }
+
+    // The items of a proper list.
+    pub fn list_items(&self) -> LangResult<Vec<Expr>> {
+        let mut items = vec![];
+        let mut rest = self.clone();
+        loop {
+            rest = match rest {
+                Expr::Nil => return Ok(items),
+                Expr::Pair(pair) => {
+                    items.push(pair.car.borrow().clone());
+                    let cdr = pair.cdr.borrow().clone();
+                    cdr
+                }
+                _ => return Err(LangError::TypeMismatched),
+            }
+        }
+    }
+
+    // Whether `self` is `nil` or a chain of pairs ending with `nil`.
+    // Cyclic lists are detected with the tortoise and hare algorithm.
+    pub fn is_list(&self) -> bool {
+        let mut slow = self.clone();
+        let mut fast = self.clone();
+        loop {
+            for _ in 0..2 {
+                fast = match fast {
+                    Expr::Nil => return true,
+                    Expr::Pair(pair) => pair.cdr.borrow().clone(),
+                    _ => return false,
+                };
+            }
+            slow = slow.cdr().unwrap();
+            if let (Expr::Pair(x), Expr::Pair(y)) = (&slow, &fast) {
+                if Rc::ptr_eq(x, y) {
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Converts a quoted expression into the value it denotes:
+    // s-expressions become freshly allocated lists, atoms stay the same.
+    // Vectors are copied as well, so mutating the value never changes the code.
+    pub fn to_datum(&self) -> Expr {
+        match self {
+            Expr::SExpr(s_expr) => Expr::list(s_expr.iter().map(Expr::to_datum), Expr::Nil),
+            Expr::Pair(pair) => {
+                Expr::cons(pair.car.borrow().to_datum(), pair.cdr.borrow().to_datum())
+            }
+            Expr::Vector(vector) => {
+                Expr::vector(vector.borrow().iter().map(Expr::to_datum).collect())
+            }
+            expr => expr.clone(),
+        }
+    }
+
+    // The inverse of `to_datum`: converts a value back into code,
+    // proper lists become s-expressions again, improper ones dotted lists.
+    // The empty list becomes `()`, because `nil` evaluates to the same value,
+    // but only `()` is an empty list of bindings or parameters, like in `(let () x)`.
+    pub fn to_syntax(&self) -> Expr {
+        match self {
+            Expr::Nil => Expr::SExpr(Rc::new([])),
+            Expr::Pair(_) => {
+                let mut items = vec![];
+                let mut rest = self.clone();
+                while let Expr::Pair(pair) = rest {
+                    items.push(pair.car.borrow().to_syntax());
+                    rest = pair.cdr.borrow().clone();
+                }
+                match rest {
+                    Expr::Nil => Expr::SExpr(items.into()),
+                    tail => Expr::list(items, tail.to_syntax()),
+                }
+            }
+            expr => expr.clone(),
+        }
+    }
+}
+
+// Prints a value for `write`.
+// Unlike `Display`, which prints the content of strings and characters,
+// strings and characters are printed as literals,
+// so every readable value reads back as an equal value.
+pub struct Written<'a>(pub &'a Expr);
+
+impl Display for Written<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.print(true))
+    }
 }
 
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let str = match self {
+        write!(f, "{}", self.print(false))
+    }
+}
+
+impl Expr {
+    fn print(&self, written: bool) -> String {
+        match self {
             Expr::Nil => "nil".to_string(),
+            Expr::Symbol(s)
+                if written && s.original().is_none() && !reads_as_symbol(s.as_str()) =>
+            {
+                format!("|{}|", s.as_str().replace('\\', "\\\\").replace('|', "\\|"))
+            }
             Expr::Symbol(s) => s.to_string(),
             Expr::Num(n) => n.to_string(),
-            Expr::String(s) => format!("\"{}\"", s.clone()),
+            Expr::String(s) if written => format!("\"{}\"", escape_string(s)),
+            Expr::String(s) => s.to_string(),
+            Expr::Char(c) if written => match CHAR_NAMES.iter().find(|(_, named)| named == c) {
+                Some((name, _)) => format!("#\\{name}"),
+                None if c.is_control() => format!("#\\x{:x}", *c as u32),
+                None => format!("#\\{c}"),
+            },
+            Expr::Char(c) => c.to_string(),
             Expr::Bool(b) => String::from(if *b { "#t" } else { "#f" }),
             Expr::SExpr(s_expr) => {
-                let xs: Vec<String> = s_expr.iter().map(|s| s.to_string()).collect();
+                let xs: Vec<String> = s_expr.iter().map(|s| s.print(written)).collect();
+                format!("({})", xs.join(" "))
+            }
+            Expr::Vector(vector) => {
+                let xs: Vec<String> = vector.borrow().iter().map(|e| e.print(written)).collect();
+                format!("#({})", xs.join(" "))
+            }
+            Expr::Pair(pair) => {
+                let mut xs = vec![pair.car.borrow().print(written)];
+                let mut rest = pair.cdr.borrow().clone();
+                loop {
+                    rest = match rest {
+                        Expr::Nil => break,
+                        Expr::Pair(pair) => {
+                            xs.push(pair.car.borrow().print(written));
+                            let cdr = pair.cdr.borrow().clone();
+                            cdr
+                        }
+                        tail => {
+                            xs.push(format!(". {}", tail.print(written)));
+                            break;
+                        }
+                    }
+                }
                 format!("({})", xs.join(" "))
             }
-            Expr::QExpr(q_expr) => {
-                let q = q_expr.to_string();
-                format!("'{q}")
+            Expr::Map(map) => {
+                let xs: Vec<String> = map
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", k.print(written), v.print(written)))
+                    .collect();
+                format!("{{{}}}", xs.join(" "))
             }
+            Expr::Set(set) => {
+                let xs: Vec<String> = set.iter().map(|e| e.print(written)).collect();
+                format!("#{{{}}}", xs.join(" "))
+            }
+            Expr::HashTable(table) => format!("#<hash-table {}>", table.borrow().len()),
+            Expr::Record(record) => {
+                let values = record.values.borrow();
+                let fields = record.rtd.fields.iter().zip(values.iter());
+                let xs: Vec<String> = fields
+                    .map(|(field, value)| format!(" {}={}", field, value.print(written)))
+                    .collect();
+                format!("#<record {}{}>", record.rtd.name, xs.concat())
+            }
+            Expr::RecordProc(proc) => proc.to_string(),
             Expr::Fn(f) => format!("Function: {:?}", f), // _ => unimplemented!(),
+            Expr::Macro(_) => "Macro".to_string(),
             Expr::Lambda(lambda) => {
-                let params = lambda.params.clone();
-                let body = lambda.body.clone();
-                format!("Lambda: params: {} body: {}", params, body)
+                let mut params: Vec<&str> = lambda.params.iter().map(Symbol::as_str).collect();
+                if let Some(rest) = lambda.rest {
+                    params.extend([".", rest.as_str()]);
+                }
+                let params = params.join(" ");
+                let body: Vec<String> = lambda.body.iter().map(|e| e.print(true)).collect();
+                format!("Lambda: params: ({}) body: {}", params, body.join(" "))
+            }
+        }
+    }
+}
+
+// The names of characters in literals like `#\space`.
+const CHAR_NAMES: [(&str, char); 10] = [
+    ("alarm", '\u{7}'),
+    ("backspace", '\u{8}'),
+    ("delete", '\u{7f}'),
+    ("escape", '\u{1b}'),
+    ("newline", '\n'),
+    ("null", '\0'),
+    ("nul", '\0'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("tab", '\t'),
+];
+
+// Parses a character literal without its `#\` prefix.
+fn parse_char(literal: &str) -> LangResult<char> {
+    if let Some((_, c)) = CHAR_NAMES.iter().find(|(name, _)| *name == literal) {
+        return Ok(*c);
+    }
+    let mut chars = literal.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => literal
+            .strip_prefix('x')
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .and_then(char::from_u32)
+            .ok_or_else(|| LangError::ParseFailed(format!("invalid character: #\\{literal}"))),
+    }
+}
+
+// Decodes the escape sequences accepted by the reader.
+// A `\uXXXX` escape of a high surrogate must be followed by one of a low surrogate,
+// together they encode a single character like in JSON.
+fn unescape_string(literal: &str) -> LangResult<String> {
+    let invalid = || LangError::ParseFailed(format!("invalid escape sequence in \"{literal}\""));
+    let mut string = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        let c = match chars.next().ok_or_else(invalid)? {
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let code = read_hex(&mut chars).ok_or_else(invalid)?;
+                let code = match code {
+                    0xd800..=0xdbff => {
+                        let low = match (chars.next(), chars.next()) {
+                            (Some('\\'), Some('u')) => read_hex(&mut chars),
+                            _ => None,
+                        };
+                        match low {
+                            Some(low @ 0xdc00..=0xdfff) => {
+                                0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00)
+                            }
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    code => code,
+                };
+                char::from_u32(code).ok_or_else(invalid)?
             }
+            c => c,
         };
-        write!(f, "{}", str)
+        string.push(c);
+    }
+
+    Ok(string)
+}
+
+// Exactly four hex digits, `u32::from_str_radix` alone would accept fewer or a sign.
+fn read_hex(chars: &mut std::str::Chars) -> Option<u32> {
+    let hex: String = chars.take(4).collect();
+    match hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => u32::from_str_radix(&hex, 16).ok(),
+        false => None,
+    }
+}
+
+// The inverse of `unescape_string`, so printed strings can be read back.
+fn escape_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Whether `name` is read back as the symbol with this name,
+// otherwise `write` prints it between vertical bars.
+// e.g. `1` is read as a number and `a b` as two symbols.
+fn reads_as_symbol(name: &str) -> bool {
+    let plain = |c: char| c.is_ascii_alphabetic() || "-*/%^=<>!_&$?".contains(c);
+    if name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && !name.starts_with("nil")
+        && name.chars().all(|c| plain(c) || c.is_ascii_digit())
+    {
+        return true;
+    }
+    let Ok(mut parsed) = LangParser::parse(Rule::expr, name) else {
+        return false;
+    };
+    match parsed.next().and_then(|expr| expr.into_inner().next()) {
+        Some(inner) => inner.as_rule() == Rule::symbol && inner.as_str() == name,
+        None => false,
     }
 }
 
-pub fn from(parsed_exprs: Pair<Rule>) -> LangResult<Vec<Expr>> {
+pub fn from(parsed_exprs: iterators::Pair<Rule>) -> LangResult<Vec<Expr>> {
     let mut ast = vec![];
 
     for expr in parsed_exprs.into_inner() {
         for inner_expr in expr.into_inner() {
             let new_expr = match inner_expr.as_rule() {
                 Rule::nil => Expr::Nil,
-                Rule::num => {
-                    let num = inner_expr
-                        .as_str()
-                        .parse()
-                        .map_err(|e| LangError::ParseFailed(format!("{e}")))?;
-                    Expr::Num(num)
+                Rule::num => Expr::Num(inner_expr.as_str().parse()?),
+                Rule::string => {
+                    let inner = inner_expr.into_inner().next().unwrap();
+                    match inner.as_rule() {
+                        Rule::raw_string_interior => Expr::String(inner.as_str().into()),
+                        _ => Expr::String(unescape_string(inner.as_str())?.into()),
+                    }
                 }
-                Rule::string => Expr::String(inner_expr.into_inner().as_str().into()),
+                Rule::character => Expr::Char(parse_char(&inner_expr.as_str()[2..])?),
                 Rule::bool => Expr::Bool(inner_expr.as_str() == "#t"),
-                Rule::symbol => Expr::Symbol(inner_expr.as_str().to_string()),
+                Rule::symbol => Expr::Symbol(inner_expr.as_str().into()),
+                Rule::quoted_symbol => {
+                    let inner = inner_expr.into_inner().next().unwrap();
+                    let mut name = String::with_capacity(inner.as_str().len());
+                    let mut chars = inner.as_str().chars();
+                    while let Some(c) = chars.next() {
+                        // The grammar only accepts `\|` and `\\` escapes.
+                        name.push(match c {
+                            '\\' => chars.next().unwrap(),
+                            c => c,
+                        });
+                    }
+                    Expr::Symbol(name.into())
+                }
                 Rule::s_expr => {
                     // let input = add_bracket(inner_expr.as_str());
                     let input = inner_expr.as_str();
@@ -117,9 +686,31 @@ pub fn from(parsed_exprs: Pair<Rule>) -> LangResult<Vec<Expr>> {
                             .next()
                             .unwrap();
                     let s_expr = from(s_expr)?;
-                    Expr::SExpr(s_expr)
+                    Expr::SExpr(s_expr.into())
+                }
+                // Kept as code like s-expressions, e.g. the parameters `(x . rest)`,
+                // quoting converts it into data.
+                Rule::dotted_list => {
+                    let mut items = from(inner_expr)?;
+                    let tail = items.pop().unwrap();
+                    Expr::list(items, tail)
+                }
+                // Vectors are self-evaluating, their items aren't evaluated.
+                // They are kept as code like lists, so they can be quasiquoted.
+                Rule::vector => Expr::vector(from(inner_expr)?),
+                // Maps and sets are self-evaluating like vectors.
+                Rule::map => Expr::map(from(inner_expr)?.iter().map(Expr::to_datum).collect())?,
+                Rule::set => Expr::set(from(inner_expr)?.iter().map(Expr::to_datum).collect()),
+                Rule::q_expr | Rule::quasi_quote | Rule::unquote | Rule::unquote_splicing => {
+                    let keyword = match inner_expr.as_rule() {
+                        Rule::q_expr => "quote",
+                        Rule::quasi_quote => "quasiquote",
+                        Rule::unquote => "unquote",
+                        _ => "unquote-splicing",
+                    };
+                    let keyword = Expr::Symbol(keyword.into());
+                    Expr::SExpr([keyword, from(inner_expr)?[0].clone()].into())
                 }
-                Rule::q_expr => Expr::QExpr(Box::new(from(inner_expr)?[0].clone())),
                 _ => unimplemented!(),
             };
 
@@ -129,3 +720,48 @@ pub fn from(parsed_exprs: Pair<Rule>) -> LangResult<Vec<Expr>> {
 
     Ok(ast)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_simple_escapes() {
+        assert_eq!(unescape_string(r#"a\"b\\c\/"#).unwrap(), "a\"b\\c/");
+        assert_eq!(unescape_string(r"\b\f\n\r\t").unwrap(), "\u{8}\u{c}\n\r\t");
+        assert_eq!(unescape_string(r"\u0041\u00e9").unwrap(), "A\u{e9}");
+    }
+
+    #[test]
+    fn unescape_surrogate_pairs() {
+        assert_eq!(unescape_string(r"\ud83d\ude00").unwrap(), "\u{1f600}");
+        assert_eq!(unescape_string(r"x\ud834\udd1ey").unwrap(), "x\u{1d11e}y");
+    }
+
+    #[test]
+    fn unescape_rejects_invalid_escapes() {
+        // A high surrogate alone, or followed by anything but a low surrogate.
+        assert!(unescape_string(r"\ud83d").is_err());
+        assert!(unescape_string(r"\ud83dx").is_err());
+        assert!(unescape_string(r"\ud83d\u0041").is_err());
+        // A low surrogate alone.
+        assert!(unescape_string(r"\ude00").is_err());
+        assert!(unescape_string(r"\u12").is_err());
+        assert!(unescape_string("\\").is_err());
+    }
+
+    #[test]
+    fn symbols_are_written_readably() {
+        let written = |name: &str| Written(&Expr::Symbol(name.into())).to_string();
+        assert_eq!(written("foo-bar?"), "foo-bar?");
+        assert_eq!(written("..."), "...");
+        assert_eq!(written("a b"), "|a b|");
+        assert_eq!(written("1"), "|1|");
+        assert_eq!(written(""), "||");
+        assert_eq!(written(r"a|b\c"), r"|a\|b\\c|");
+        for name in ["a b", "1", "", r"a|b\c", "nil"] {
+            let read = crate::eval(&written(name)).unwrap();
+            assert!(matches!(&read[..], [Expr::Symbol(s)] if s == name));
+        }
+    }
+}
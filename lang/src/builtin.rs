@@ -1,71 +1,510 @@
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, rc::Rc};
+
+use indexmap::{IndexMap, IndexSet};
+
 use crate::{
-    ast::Expr,
+    ast::{Expr, Written},
     env::Env,
-    utils::{ensure, parse_list_of_floats, parse_list_of_strings},
-    LangError,
+    eval::apply,
+    format::format,
+    number::Number,
+    symbol::Symbol,
+    utils::{
+        expected, parse_index, parse_list_of_chars, parse_list_of_numbers, parse_list_of_strings,
+        parse_radix, parse_string, slice_chars,
+    },
+    LangError, LangResult,
 };
 
+// Defines a predicate taking a single argument of any type.
+macro_rules! is {
+    ($env:ident, $a:expr => $x:ident => $body:expr) => {
+        $env.define(
+            $a.into(),
+            Expr::Fn(|args| match args {
+                [$x] => Ok(Expr::Bool($body)),
+                _ => Err(LangError::InvalidArgsLen),
+            }),
+        );
+    };
+}
+
+// Defines a procedure taking a fixed number of numbers.
+macro_rules! numeric {
+    ($env:ident, $a:expr => [$($x:ident),+] => $body:expr) => {
+        $env.define(
+            $a.into(),
+            Expr::Fn(|args| match &parse_list_of_numbers(args)?[..] {
+                [$($x),+] => Ok($body),
+                _ => Err(LangError::InvalidArgsLen),
+            }),
+        );
+    };
+}
+
 pub fn define_std(env: &mut Env) {
     define_string(env);
+    define_symbol(env);
+    define_char(env);
     define_list(env);
+    define_vector(env);
+    define_hash_table(env);
+    define_map(env);
+    define_set(env);
     define_compare(env);
     define_equal(env);
     define_add(env);
     define_sub(env);
     define_mul(env);
     define_div(env);
+    define_exactness(env);
+    define_math(env);
     define_io(env);
     define_other(env);
 }
 
 fn define_list(env: &mut Env) {
+    // The operations are listed in the order they are applied,
+    // which is the reverse of the letters in the name: `cadr` is `(car (cdr x))`.
     #[rustfmt::skip]
-    macro_rules! nth {
-        ($a:expr => $n:expr) => {
+    macro_rules! cxr {
+        ($a:expr => $($op:ident)+) => {
             env.define($a.into(), Expr::Fn(|args| {
-                let first = args.first().cloned().ok_or(LangError::InvalidArgsLen)?;
-                let args = first.inner_q_expr()?.clone();
-                Ok(match *args {
-                    Expr::SExpr(args) => args.get($n).cloned().ok_or(LangError::InvalidArgsLen)?,
-                    e => e,
-                })
+                let [x] = args else { return Err(LangError::InvalidArgsLen) };
+                let x = x.clone();
+                $(let x = x.$op()?;)+
+                Ok(x)
             }));
         };
     }
 
+    macro_rules! set {
+        ($a:expr => $field:ident) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| {
+                    let [pair, value] = args else {
+                        return Err(LangError::InvalidArgsLen);
+                    };
+                    *pair.inner_pair()?.$field.borrow_mut() = value.clone();
+                    Ok(Expr::Nil)
+                }),
+            );
+        };
+    }
+
     env.define(
         "list".into(),
+        Expr::Fn(|args| Ok(Expr::list(args.iter().cloned(), Expr::Nil))),
+    );
+    env.define(
+        "cons".into(),
+        Expr::Fn(|args| match args {
+            [car, cdr] => Ok(Expr::cons(car.clone(), cdr.clone())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    cxr!("car" => car);
+    cxr!("cdr" => cdr);
+    cxr!("caar" => car car);
+    cxr!("cadr" => cdr car);
+    cxr!("cdar" => car cdr);
+    cxr!("cddr" => cdr cdr);
+    cxr!("caddr" => cdr cdr car);
+    cxr!("cdddr" => cdr cdr cdr);
+    cxr!("cadddr" => cdr cdr cdr car);
+
+    set!("set-car!" => car);
+    set!("set-cdr!" => cdr);
+
+    is!(env, "pair?" => x => matches!(x, Expr::Pair(_)));
+    is!(env, "null?" => x => matches!(x, Expr::Nil));
+    is!(env, "list?" => x => x.is_list());
+}
+fn define_vector(env: &mut Env) {
+    macro_rules! vector_fn {
+        ($a:expr => [$vector:ident $(, $x:ident)*] => $body:expr) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [$vector $(, $x)*] => {
+                        let $vector = $vector.inner_vector()?;
+                        Ok($body)
+                    }
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    // Checks the index against the length of the vector.
+    fn parse_vector_index(vector: &[Expr], idx: &Expr) -> LangResult<usize> {
+        match parse_index(idx)? {
+            idx if idx < vector.len() => Ok(idx),
+            idx => Err(LangError::Other(format!("index out of range: {idx}"))),
+        }
+    }
+
+    // The items of the vectors at the same index, up to the shortest vector.
+    fn transpose(vectors: &[Expr]) -> LangResult<Vec<Vec<Expr>>> {
+        let vectors = vectors
+            .iter()
+            .map(|vector| Ok(vector.inner_vector()?.borrow().clone()))
+            .collect::<LangResult<Vec<_>>>()?;
+        let len = vectors.iter().map(Vec::len).min().unwrap_or(0);
+        let items = (0..len).map(|idx| vectors.iter().map(move |vector| vector[idx].clone()));
+        Ok(items.map(Iterator::collect).collect())
+    }
+
+    is!(env, "vector?" => x => matches!(x, Expr::Vector(_)));
+
+    env.define(
+        "vector".into(),
+        Expr::Fn(|args| Ok(Expr::vector(args.to_vec()))),
+    );
+    // The items are `0` when `fill` is omitted.
+    env.define(
+        "make-vector".into(),
         Expr::Fn(|args| {
-            let args = Expr::SExpr(args.to_vec());
-            Ok(Expr::QExpr(Box::new(args)))
+            let (len, fill) = match args {
+                [len] => (len, Expr::Num(Number::Int(0))),
+                [len, fill] => (len, fill.clone()),
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            Ok(Expr::vector(vec![fill; parse_index(len)?]))
         }),
     );
+    env.define(
+        "list->vector".into(),
+        Expr::Fn(|args| match args {
+            [list] => Ok(Expr::vector(list.list_items()?)),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    vector_fn!("vector-length" => [v] => Expr::Num(Number::Int(v.borrow().len() as i64)));
+    vector_fn!("vector-ref" => [v, idx] => {
+        let v = v.borrow();
+        v[parse_vector_index(&v, idx)?].clone()
+    });
+    vector_fn!("vector-set!" => [v, idx, value] => {
+        let mut v = v.borrow_mut();
+        let idx = parse_vector_index(&v, idx)?;
+        v[idx] = value.clone();
+        Expr::Nil
+    });
+    vector_fn!("vector-fill!" => [v, fill] => {
+        v.borrow_mut().fill(fill.clone());
+        Expr::Nil
+    });
+    vector_fn!("vector->list" => [v] => Expr::list(v.borrow().clone(), Expr::Nil));
+    vector_fn!("vector-copy" => [v] => Expr::vector(v.borrow().clone()));
 
-    nth!("car" => 0);
-    nth!("cdr" => 1);
-    nth!("cadr" => 2);
-    nth!("caddr" => 3);
-    nth!("cadddr" => 4);
+    // (vector-map proc vector ...)
+    env.define(
+        "vector-map".into(),
+        Expr::Fn(|args| match args {
+            [proc, vectors @ ..] if !vectors.is_empty() => {
+                let items = transpose(vectors)?
+                    .into_iter()
+                    .map(|args| apply(proc, args))
+                    .collect::<LangResult<_>>()?;
+                Ok(Expr::vector(items))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (vector-for-each proc vector ...)
+    env.define(
+        "vector-for-each".into(),
+        Expr::Fn(|args| match args {
+            [proc, vectors @ ..] if !vectors.is_empty() => {
+                for args in transpose(vectors)? {
+                    apply(proc, args)?;
+                }
+                Ok(Expr::Nil)
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
 }
-fn define_other(env: &mut Env) {
+
+// Keys are compared with `equal?`, see the `PartialEq` of `Expr`.
+fn define_hash_table(env: &mut Env) {
+    macro_rules! table_fn {
+        ($a:expr => [$table:ident $(, $x:ident)*] => $body:expr) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [$table $(, $x)*] => {
+                        let $table = $table.inner_hash_table()?;
+                        Ok($body)
+                    }
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    // The value of `key`, or the result of calling `default` without arguments.
+    // The table isn't borrowed while `default` runs, so it may use the table.
+    fn lookup(
+        table: &RefCell<HashMap<Expr, Expr>>,
+        key: &Expr,
+        default: Option<&Expr>,
+    ) -> LangResult<Expr> {
+        let value = table.borrow().get(key).cloned();
+        match (value, default) {
+            (Some(value), _) => Ok(value),
+            (None, Some(default)) => apply(default, vec![]),
+            (None, None) => Err(LangError::Other(format!("key not found: {}", Written(key)))),
+        }
+    }
+
+    is!(env, "hash-table?" => x => matches!(x, Expr::HashTable(_)));
+
     env.define(
-        "begin".into(),
-        Expr::Fn(|args| args.iter().last().cloned().ok_or(LangError::InvalidArgsLen)),
+        "make-hash-table".into(),
+        Expr::Fn(|args| match args {
+            [] => Ok(Expr::HashTable(Rc::default())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
     );
 
+    table_fn!("hash-table-set!" => [table, key, value] => {
+        table.borrow_mut().insert(key.clone(), value.clone());
+        Expr::Nil
+    });
+    table_fn!("hash-table-ref/default" => [table, key, default] => {
+        table.borrow().get(key).unwrap_or(default).clone()
+    });
+    table_fn!("hash-table-contains?" => [table, key] => Expr::Bool(table.borrow().contains_key(key)));
+    table_fn!("hash-table-delete!" => [table, key] => {
+        table.borrow_mut().remove(key);
+        Expr::Nil
+    });
+    table_fn!("hash-table-count" => [table] => Expr::Num(Number::Int(table.borrow().len() as i64)));
+    table_fn!("hash-table-keys" => [table] => Expr::list(table.borrow().keys().cloned().collect::<Vec<_>>(), Expr::Nil));
+    table_fn!("hash-table-values" => [table] => Expr::list(table.borrow().values().cloned().collect::<Vec<_>>(), Expr::Nil));
+    table_fn!("hash-table->alist" => [table] => {
+        let pairs = table.borrow().iter().map(|(key, value)| Expr::cons(key.clone(), value.clone())).collect::<Vec<_>>();
+        Expr::list(pairs, Expr::Nil)
+    });
+
+    // (hash-table-ref table key [default])
+    env.define(
+        "hash-table-ref".into(),
+        Expr::Fn(|args| match args {
+            [table, key] => lookup(table.inner_hash_table()?, key, None),
+            [table, key, default] => lookup(table.inner_hash_table()?, key, Some(default)),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (hash-table-update! table key proc [default])
+    // Sets `key` to `proc` applied to its value, `default` is called when it's missing.
     env.define(
-        "when".into(),
+        "hash-table-update!".into(),
         Expr::Fn(|args| {
-            if args.len() < 2 {
-                return Err(LangError::InvalidArgsLen);
-            }
-            let cond = args[0].inner_bool()?;
+            let (table, key, proc, default) = match args {
+                [table, key, proc] => (table, key, proc, None),
+                [table, key, proc, default] => (table, key, proc, Some(default)),
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            let table = table.inner_hash_table()?;
+            let value = apply(proc, vec![lookup(table, key, default)?])?;
+            table.borrow_mut().insert(key.clone(), value);
+            Ok(Expr::Nil)
+        }),
+    );
+}
 
-            let result = match cond {
-                true => args.last().cloned().unwrap(),
-                false => Expr::Nil,
+// Maps are immutable, every update returns a new map.
+fn define_map(env: &mut Env) {
+    macro_rules! map_fn {
+        ($a:expr => [$map:ident $(, $x:ident)*] => $body:expr) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [$map $(, $x)*] => {
+                        let $map = $map.inner_map()?;
+                        Ok($body)
+                    }
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    is!(env, "map?" => x => matches!(x, Expr::Map(_)));
+
+    env.define("hash-map".into(), Expr::Fn(|args| Expr::map(args.to_vec())));
+
+    // (map-ref map key [default])
+    env.define(
+        "map-ref".into(),
+        Expr::Fn(|args| {
+            let (map, key, default) = match args {
+                [map, key] => (map, key, None),
+                [map, key, default] => (map, key, Some(default)),
+                _ => return Err(LangError::InvalidArgsLen),
             };
-            Ok(result)
+            match (map.inner_map()?.get(key), default) {
+                (Some(value), _) | (None, Some(value)) => Ok(value.clone()),
+                (None, None) => Err(LangError::Other(format!("key not found: {}", Written(key)))),
+            }
+        }),
+    );
+    // (map-assoc map key value ...)
+    env.define(
+        "map-assoc".into(),
+        Expr::Fn(|args| match args {
+            [map, entries @ ..] if entries.len() % 2 == 0 => {
+                let mut map = IndexMap::clone(map.inner_map()?);
+                for entry in entries.chunks(2) {
+                    map.insert(entry[0].clone(), entry[1].clone());
+                }
+                Ok(Expr::Map(Rc::new(map)))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (map-dissoc map key ...)
+    env.define(
+        "map-dissoc".into(),
+        Expr::Fn(|args| match args {
+            [map, keys @ ..] => {
+                let mut map = IndexMap::clone(map.inner_map()?);
+                for key in keys {
+                    map.shift_remove(key);
+                }
+                Ok(Expr::Map(Rc::new(map)))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (map-union map ...), the value of a key comes from the last map having it.
+    env.define(
+        "map-union".into(),
+        Expr::Fn(|args| {
+            let mut union = IndexMap::new();
+            for map in args {
+                union.extend(map.inner_map()?.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            Ok(Expr::Map(Rc::new(union)))
+        }),
+    );
+
+    map_fn!("map-contains?" => [map, key] => Expr::Bool(map.contains_key(key)));
+    map_fn!("map-count" => [map] => Expr::Num(Number::Int(map.len() as i64)));
+    map_fn!("map-keys" => [map] => Expr::list(map.keys().cloned().collect::<Vec<_>>(), Expr::Nil));
+    map_fn!("map-values" => [map] => Expr::list(map.values().cloned().collect::<Vec<_>>(), Expr::Nil));
+    map_fn!("map->alist" => [map] => {
+        let pairs = map.iter().map(|(key, value)| Expr::cons(key.clone(), value.clone())).collect::<Vec<_>>();
+        Expr::list(pairs, Expr::Nil)
+    });
+}
+
+// Sets are immutable, every update returns a new set.
+fn define_set(env: &mut Env) {
+    // Copies `set` with `f` applied to the copy.
+    fn update(args: &[Expr], f: fn(&mut IndexSet<Expr>, &Expr)) -> LangResult<Expr> {
+        match args {
+            [set, items @ ..] => {
+                let mut set = IndexSet::clone(set.inner_set()?);
+                items.iter().for_each(|item| f(&mut set, item));
+                Ok(Expr::Set(Rc::new(set)))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }
+    }
+
+    is!(env, "set?" => x => matches!(x, Expr::Set(_)));
+
+    env.define(
+        "hash-set".into(),
+        Expr::Fn(|args| Ok(Expr::set(args.to_vec()))),
+    );
+    env.define(
+        "list->set".into(),
+        Expr::Fn(|args| match args {
+            [list] => Ok(Expr::set(list.list_items()?)),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "set->list".into(),
+        Expr::Fn(|args| match args {
+            [set] => Ok(Expr::list(
+                set.inner_set()?.iter().cloned().collect::<Vec<_>>(),
+                Expr::Nil,
+            )),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "set-contains?".into(),
+        Expr::Fn(|args| match args {
+            [set, item] => Ok(Expr::Bool(set.inner_set()?.contains(item))),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "set-count".into(),
+        Expr::Fn(|args| match args {
+            [set] => Ok(Expr::Num(Number::Int(set.inner_set()?.len() as i64))),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    // (set-add set item ...)
+    env.define(
+        "set-add".into(),
+        Expr::Fn(|args| {
+            update(args, |set, item| {
+                set.insert(item.clone());
+            })
+        }),
+    );
+    // (set-remove set item ...)
+    env.define(
+        "set-remove".into(),
+        Expr::Fn(|args| {
+            update(args, |set, item| {
+                set.shift_remove(item);
+            })
+        }),
+    );
+    // (set-union set ...)
+    env.define(
+        "set-union".into(),
+        Expr::Fn(|args| {
+            let mut union = IndexSet::new();
+            for set in args {
+                union.extend(set.inner_set()?.iter().cloned());
+            }
+            Ok(Expr::Set(Rc::new(union)))
+        }),
+    );
+}
+
+fn define_other(env: &mut Env) {
+    env.define(
+        "not".into(),
+        Expr::Fn(|args| match args {
+            [x] => Ok(Expr::Bool(!x.is_truthy())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (apply proc arg ... list)
+    env.define(
+        "apply".into(),
+        Expr::Fn(|args| match args {
+            [proc, init @ .., list] => {
+                let args = [init, &list.list_items()?].concat();
+                apply(proc, args)
+            }
+            _ => Err(LangError::InvalidArgsLen),
         }),
     );
 }
@@ -86,6 +525,72 @@ fn define_io(env: &mut Env) {
             Ok(Expr::Nil)
         }),
     );
+    env.define(
+        "write".into(),
+        Expr::Fn(|args| {
+            args.iter().for_each(|e| print!("{}", Written(e)));
+            Ok(Expr::Nil)
+        }),
+    );
+    env.define(
+        "writeln".into(),
+        Expr::Fn(|args| {
+            args.iter().for_each(|e| print!("{}", Written(e)));
+            println!();
+            Ok(Expr::Nil)
+        }),
+    );
+    env.define(
+        "write-string".into(),
+        Expr::Fn(|args| {
+            parse_list_of_strings(args)?
+                .iter()
+                .for_each(|s| print!("{s}"));
+            Ok(Expr::Nil)
+        }),
+    );
+    env.define(
+        "write-char".into(),
+        Expr::Fn(|args| {
+            parse_list_of_chars(args)?
+                .iter()
+                .for_each(|c| print!("{c}"));
+            Ok(Expr::Nil)
+        }),
+    );
+    env.define(
+        "printf".into(),
+        Expr::Fn(|args| match args {
+            [] => Err(LangError::InvalidArgsLen),
+            _ => {
+                print!("{}", format(&parse_string(args, 0)?, &args[1..])?);
+                Ok(Expr::Nil)
+            }
+        }),
+    );
+    // `(format [destination] control arg ...)`, see `format::format`.
+    // The result is printed if the destination is `#t`,
+    // otherwise it's returned as a string.
+    env.define(
+        "format".into(),
+        Expr::Fn(|args| {
+            let (print, idx) = match args.first() {
+                Some(Expr::Bool(print)) => (*print, 1),
+                _ => (false, 0),
+            };
+            if args.len() <= idx {
+                return Err(LangError::InvalidArgsLen);
+            }
+            let string = format(&parse_string(args, idx)?, &args[idx + 1..])?;
+            match print {
+                true => {
+                    print!("{string}");
+                    Ok(Expr::Nil)
+                }
+                false => Ok(Expr::String(string.into())),
+            }
+        }),
+    );
     env.define(
         "newline".into(),
         Expr::Fn(|args| match args.is_empty() {
@@ -108,13 +613,295 @@ fn define_string(env: &mut Env) {
             Ok(Expr::String(string.into()))
         }),
     );
+    env.define(
+        "string".into(),
+        Expr::Fn(|args| {
+            let string: String = parse_list_of_chars(args)?.into_iter().collect();
+            Ok(Expr::String(string.into()))
+        }),
+    );
+    // Indexes count characters, not bytes.
+    env.define(
+        "string-ref".into(),
+        Expr::Fn(|args| match args {
+            [_, idx] => {
+                let string = parse_string(args, 0)?;
+                let idx = parse_index(idx)?;
+                let c = string.chars().nth(idx);
+                let c = c.ok_or_else(|| LangError::Other(format!("index out of range: {idx}")))?;
+                Ok(Expr::Char(c))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "string->list".into(),
+        Expr::Fn(|args| match args {
+            [string] => {
+                let chars: Vec<_> = string.inner_string()?.chars().map(Expr::Char).collect();
+                Ok(Expr::list(chars, Expr::Nil))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "list->string".into(),
+        Expr::Fn(|args| match args {
+            [list] => {
+                let items = list
+                    .list_items()
+                    .map_err(|_| expected("a list of characters", 0, list))?;
+                let string = items
+                    .iter()
+                    .map(|c| match c {
+                        Expr::Char(c) => Ok(*c),
+                        _ => Err(expected("a list of characters", 0, list)),
+                    })
+                    .collect::<LangResult<String>>()?;
+                Ok(Expr::String(string.into()))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    // Defines a procedure taking a single string.
+    macro_rules! string_fn {
+        ($a:expr => $s:ident => $body:expr) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [_] => {
+                        let $s = parse_string(args, 0)?;
+                        Ok($body)
+                    }
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    macro_rules! compare {
+        ($a:expr => $op:tt) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match &parse_list_of_strings(args)?[..] {
+                    [_] | [] => Err(LangError::InvalidArgsLen),
+                    strings => Ok(Expr::Bool(strings.windows(2).all(|w| w[0] $op w[1]))),
+                }),
+            );
+        };
+    }
+
+    // `(substring string start [end])`, indexes count characters.
+    fn substring(args: &[Expr]) -> LangResult<Expr> {
+        let (start, end) = match args {
+            [_] => (0, None),
+            [_, start] => (parse_index(start)?, None),
+            [_, start, end] => (parse_index(start)?, Some(parse_index(end)?)),
+            _ => return Err(LangError::InvalidArgsLen),
+        };
+        let string = parse_string(args, 0)?;
+        Ok(Expr::String(slice_chars(&string, start, end)?.into()))
+    }
+
+    // Character positions, or `#f` when nothing is found.
+    fn position(idx: Option<usize>) -> Expr {
+        match idx {
+            Some(idx) => Expr::Num(Number::Int(idx as i64)),
+            None => Expr::Bool(false),
+        }
+    }
+
+    is!(env, "string?" => x => matches!(x, Expr::String(_)));
+
+    string_fn!("string-length" => s => Expr::Num(Number::Int(s.chars().count() as i64)));
+    string_fn!("string-upcase" => s => Expr::String(s.to_uppercase().into()));
+    string_fn!("string-downcase" => s => Expr::String(s.to_lowercase().into()));
+    string_fn!("string-trim" => s => Expr::String(s.trim().into()));
+    string_fn!("string-trim-left" => s => Expr::String(s.trim_start().into()));
+    string_fn!("string-trim-right" => s => Expr::String(s.trim_end().into()));
+
+    env.define("substring".into(), Expr::Fn(substring));
+    env.define("string-copy".into(), Expr::Fn(substring));
+    env.define(
+        "string-index".into(),
+        Expr::Fn(|args| match args {
+            [_, c] => {
+                let string = parse_string(args, 0)?;
+                let Expr::Char(c) = *c else {
+                    return Err(expected("a character", 1, c));
+                };
+                Ok(position(string.chars().position(|x| x == c)))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "string-contains".into(),
+        Expr::Fn(|args| match &parse_list_of_strings(args)?[..] {
+            [string, pattern] => {
+                let offset = string.find(pattern.as_ref());
+                Ok(position(
+                    offset.map(|offset| string[..offset].chars().count()),
+                ))
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "string-replace".into(),
+        Expr::Fn(|args| match &parse_list_of_strings(args)?[..] {
+            [string, from, to] => Ok(Expr::String(string.replace(from.as_ref(), to).into())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // Splits on whitespace without a separator,
+    // the separator can be a string or a character.
+    env.define(
+        "string-split".into(),
+        Expr::Fn(|args| {
+            let string = match args {
+                [_] | [_, _] => parse_string(args, 0)?,
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            let parts: Vec<&str> = match args.get(1) {
+                None => string.split_whitespace().collect(),
+                Some(Expr::Char(c)) => string.split(*c).collect(),
+                Some(Expr::String(sep)) => string.split(sep.as_ref()).collect(),
+                Some(sep) => return Err(expected("a string or a character", 1, sep)),
+            };
+            let parts = parts.into_iter().map(|part| Expr::String(part.into()));
+            Ok(Expr::list(parts.collect::<Vec<_>>(), Expr::Nil))
+        }),
+    );
+    // `(string-join list [delimiter])`, the delimiter defaults to a space.
+    env.define(
+        "string-join".into(),
+        Expr::Fn(|args| {
+            let (list, delimiter) = match args {
+                [list] => (list, " ".into()),
+                [list, _] => (list, parse_string(args, 1)?),
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            let strings = list
+                .list_items()
+                .and_then(|items| parse_list_of_strings(&items))
+                .map_err(|_| expected("a list of strings", 0, list))?;
+            Ok(Expr::String(strings.join(&delimiter).into()))
+        }),
+    );
+    compare!("string=?" => ==);
+    compare!("string<?" => <);
+    compare!("string>?" => >);
+    compare!("string<=?" => <=);
+    compare!("string>=?" => >=);
+}
+
+fn define_symbol(env: &mut Env) {
+    is!(env, "symbol?" => x => matches!(x, Expr::Symbol(_)));
+
+    env.define(
+        "string->symbol".into(),
+        Expr::Fn(|args| match args {
+            [_] => Ok(Expr::Symbol(parse_string(args, 0)?.as_ref().into())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "symbol->string".into(),
+        Expr::Fn(|args| match args {
+            [symbol] => Ok(Expr::String(symbol.inner_symbol()?.as_str().into())),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    // (gensym [prefix])
+    // A fresh symbol for macros written by hand, it never equals a symbol that is read.
+    env.define(
+        "gensym".into(),
+        Expr::Fn(|args| match args {
+            [] => Ok(Expr::Symbol(Symbol::gensym("g"))),
+            [_] => Ok(Expr::Symbol(Symbol::gensym(&parse_string(args, 0)?))),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+}
+
+fn define_char(env: &mut Env) {
+    macro_rules! char_fn {
+        ($a:expr => $c:ident => $body:expr) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [$c] => {
+                        let $c = *$c.inner_char()?;
+                        Ok($body)
+                    }
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    macro_rules! compare {
+        ($a:expr => $op:tt) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match parse_list_of_chars(args)?[..] {
+                    [_] | [] => Err(LangError::InvalidArgsLen),
+                    ref chars => Ok(Expr::Bool(chars.windows(2).all(|w| w[0] $op w[1]))),
+                }),
+            );
+        };
+    }
+
+    // Characters whose case mapping isn't a single character are kept as is.
+    fn single(mut chars: impl Iterator<Item = char>, c: char) -> char {
+        match (chars.next(), chars.next()) {
+            (Some(mapped), None) => mapped,
+            _ => c,
+        }
+    }
+
+    is!(env, "char?" => x => matches!(x, Expr::Char(_)));
+
+    char_fn!("char->integer" => c => Expr::Num(Number::Int(c as i64)));
+    char_fn!("char-upcase" => c => Expr::Char(single(c.to_uppercase(), c)));
+    char_fn!("char-downcase" => c => Expr::Char(single(c.to_lowercase(), c)));
+    char_fn!("char-alphabetic?" => c => Expr::Bool(c.is_alphabetic()));
+    char_fn!("char-numeric?" => c => Expr::Bool(c.is_numeric()));
+    char_fn!("char-whitespace?" => c => Expr::Bool(c.is_whitespace()));
+    char_fn!("char-upper-case?" => c => Expr::Bool(c.is_uppercase()));
+    char_fn!("char-lower-case?" => c => Expr::Bool(c.is_lowercase()));
+    char_fn!("digit-value" => c => match c.to_digit(10) {
+        Some(digit) => Expr::Num(Number::Int(digit as i64)),
+        None => Expr::Bool(false),
+    });
+
+    env.define(
+        "integer->char".into(),
+        Expr::Fn(|args| match args {
+            [Expr::Num(Number::Int(n))] => u32::try_from(*n)
+                .ok()
+                .and_then(char::from_u32)
+                .map(Expr::Char)
+                .ok_or_else(|| LangError::Other(format!("not a unicode scalar value: {n}"))),
+            [_] => Err(LangError::TypeMismatched),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    compare!("char=?" => ==);
+    compare!("char<?" => <);
+    compare!("char>?" => >);
+    compare!("char<=?" => <=);
+    compare!("char>=?" => >=);
 }
 
 fn define_add(env: &mut Env) {
     env.define(
         "+".into(),
         Expr::Fn(|args| {
-            let args = parse_list_of_floats(args)?;
+            let args = parse_list_of_numbers(args)?;
             let sum = args
                 .into_iter()
                 .reduce(|acc, e| acc + e)
@@ -128,11 +915,11 @@ fn define_sub(env: &mut Env) {
     env.define(
         "-".into(),
         Expr::Fn(|args| {
-            let args = parse_list_of_floats(args)?;
+            let args = parse_list_of_numbers(args)?;
             if args.is_empty() {
                 return Err(LangError::InvalidArgsLen);
             }
-            let first = &args[0];
+            let first = args[0].clone();
             if args.len() == 1 {
                 return Ok(Expr::Num(-first));
             }
@@ -150,7 +937,7 @@ fn define_mul(env: &mut Env) {
     env.define(
         "*".into(),
         Expr::Fn(|args| {
-            let args = parse_list_of_floats(args)?;
+            let args = parse_list_of_numbers(args)?;
             let product = args
                 .into_iter()
                 .reduce(|acc, e| acc * e)
@@ -163,41 +950,284 @@ fn define_div(env: &mut Env) {
     env.define(
         "/".into(),
         Expr::Fn(|args| {
-            let args = parse_list_of_floats(args)?;
+            let args = parse_list_of_numbers(args)?;
+            // `(/ x)` is the reciprocal of `x`, like `(- x)` is its negation.
+            if let [x] = &args[..] {
+                return Ok(Expr::Num(Number::Int(1).try_div(x)?));
+            }
             let num = args
                 .into_iter()
-                .try_reduce(|acc, e| {
-                    if e == 0.0 {
-                        Err(LangError::DivideByZero)
-                    } else {
-                        Ok(acc / e)
-                    }
-                    // todo!()
-                })?
+                .try_reduce(|acc, e| acc.try_div(&e))?
                 .ok_or(LangError::InvalidArgsLen)?;
             Ok(Expr::Num(num))
         }),
     );
+
+    numeric!(env, "quotient" => [x, y] => Expr::Num(x.quotient(y)?));
+    numeric!(env, "remainder" => [x, y] => Expr::Num(x.remainder(y)?));
+    numeric!(env, "modulo" => [x, y] => Expr::Num(x.modulo(y)?));
+}
+
+fn define_exactness(env: &mut Env) {
+    numeric!(env, "exact?" => [x] => Expr::Bool(x.is_exact()));
+    numeric!(env, "inexact?" => [x] => Expr::Bool(!x.is_exact()));
+    numeric!(env, "exact" => [x] => Expr::Num(x.to_exact()?));
+    numeric!(env, "inexact" => [x] => Expr::Num(x.to_inexact()));
+    numeric!(env, "inexact->exact" => [x] => Expr::Num(x.to_exact()?));
+    numeric!(env, "exact->inexact" => [x] => Expr::Num(x.to_inexact()));
+    numeric!(env, "numerator" => [x] => Expr::Num(x.numerator()?));
+    numeric!(env, "denominator" => [x] => Expr::Num(x.denominator()?));
+    numeric!(env, "rationalize" => [x, y] => Expr::Num(x.rationalize(y)?));
+}
+
+fn define_math(env: &mut Env) {
+    // Transcendental functions always give an inexact result.
+    macro_rules! float {
+        ($a:expr => $op:ident) => {
+            numeric!(env, $a => [x] => Expr::Num(Number::Float(x.to_f64().$op())));
+        };
+    }
+
+    // Results are inexact if any argument is.
+    macro_rules! extremum {
+        ($a:expr => $op:tt) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| {
+                    let args = parse_list_of_numbers(args)?;
+                    let exact = args.iter().all(Number::is_exact);
+                    let num = args
+                        .into_iter()
+                        .reduce(|acc, e| if e $op acc { e } else { acc })
+                        .ok_or(LangError::InvalidArgsLen)?;
+                    Ok(Expr::Num(if exact { num } else { num.to_inexact() }))
+                }),
+            );
+        };
+    }
+
+    macro_rules! fold {
+        ($a:expr => $init:expr => $op:ident) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| {
+                    // Checked up front, so an error names the argument instead of the accumulator.
+                    let nums = args
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, arg)| match arg {
+                            Expr::Num(n) if n.is_integer() => Ok(n),
+                            _ => Err(expected("an integer", idx, arg)),
+                        })
+                        .collect::<LangResult<Vec<_>>>()?;
+                    let num = nums
+                        .into_iter()
+                        .try_fold(Number::Int($init), |acc, e| acc.$op(e))?;
+                    Ok(Expr::Num(num))
+                }),
+            );
+        };
+    }
+
+    numeric!(env, "abs" => [x] => Expr::Num(x.abs()));
+    numeric!(env, "square" => [x] => Expr::Num(x.clone() * x.clone()));
+    numeric!(env, "sqrt" => [x] => Expr::Num(x.sqrt()?));
+    numeric!(env, "expt" => [x, y] => Expr::Num(x.expt(y)?));
+    numeric!(env, "floor" => [x] => Expr::Num(x.floor()));
+    numeric!(env, "ceiling" => [x] => Expr::Num(x.ceiling()));
+    numeric!(env, "round" => [x] => Expr::Num(x.round()));
+    numeric!(env, "truncate" => [x] => Expr::Num(x.truncate()));
+
+    float!("exp" => exp);
+    float!("sin" => sin);
+    float!("cos" => cos);
+    float!("tan" => tan);
+    float!("asin" => asin);
+    float!("acos" => acos);
+    env.define(
+        "atan".into(),
+        Expr::Fn(|args| match &parse_list_of_numbers(args)?[..] {
+            [x] => Ok(Expr::Num(Number::Float(x.to_f64().atan()))),
+            [y, x] => Ok(Expr::Num(Number::Float(y.to_f64().atan2(x.to_f64())))),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+    env.define(
+        "log".into(),
+        Expr::Fn(|args| match &parse_list_of_numbers(args)?[..] {
+            [x] => Ok(Expr::Num(Number::Float(x.to_f64().ln()))),
+            [x, base] => Ok(Expr::Num(Number::Float(x.to_f64().log(base.to_f64())))),
+            _ => Err(LangError::InvalidArgsLen),
+        }),
+    );
+
+    extremum!("max" => >);
+    extremum!("min" => <);
+    fold!("gcd" => 0 => gcd);
+    fold!("lcm" => 1 => lcm);
+
+    numeric!(env, "zero?" => [x] => Expr::Bool(x.is_zero()));
+    numeric!(env, "positive?" => [x] => Expr::Bool(x.is_positive()));
+    numeric!(env, "negative?" => [x] => Expr::Bool(x.is_negative()));
+    numeric!(env, "even?" => [x] => Expr::Bool(x.remainder(&Number::Int(2))?.is_zero()));
+    numeric!(env, "odd?" => [x] => Expr::Bool(!x.remainder(&Number::Int(2))?.is_zero()));
+    numeric!(env, "nan?" => [x] => Expr::Bool(x.to_f64().is_nan()));
+
+    is!(env, "number?" => x => matches!(x, Expr::Num(_)));
+    is!(env, "real?" => x => matches!(x, Expr::Num(_)));
+    is!(env, "rational?" => x => matches!(x, Expr::Num(n) if n.is_exact() || n.to_f64().is_finite()));
+    is!(env, "integer?" => x => matches!(x, Expr::Num(n) if n.is_integer()));
+    is!(env, "exact-integer?" => x => matches!(x, Expr::Num(n) if n.is_exact() && n.is_integer()));
+
+    env.define(
+        "number->string".into(),
+        Expr::Fn(|args| {
+            let (num, radix) = match args {
+                [num] => (num, None),
+                [num, radix] => (num, Some(radix)),
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            let num = num.inner_num()?.to_string_radix(parse_radix(radix)?)?;
+            Ok(Expr::String(num.into()))
+        }),
+    );
+    // Returns `#f` when the string isn't a number.
+    env.define(
+        "string->number".into(),
+        Expr::Fn(|args| {
+            let (string, radix) = match args {
+                [string] => (string, None),
+                [string, radix] => (string, Some(radix)),
+                _ => return Err(LangError::InvalidArgsLen),
+            };
+            let radix = parse_radix(radix)?;
+            match Number::from_str_radix(string.inner_string()?, radix) {
+                Ok(num) => Ok(Expr::Num(num)),
+                Err(_) => Ok(Expr::Bool(false)),
+            }
+        }),
+    );
 }
 
 fn define_compare(env: &mut Env) {
     macro_rules! ensure {
-        ($x:ident $op:tt $y:ident) => {
+        ($op:ident) => {
             |args| match args.len() <= 1 {
                 true => Err(LangError::InvalidArgsLen),
-                false => ensure(args, |$x, $y| $x $op $y)
+                false => {
+                    for pair in args.windows(2) {
+                        if !order(&pair[0], &pair[1])?.is_some_and(Ordering::$op) {
+                            return Ok(Expr::Bool(false));
+                        }
+                    }
+                    Ok(Expr::Bool(true))
+                }
             }
         };
     }
 
-    env.define(">".into(), Expr::Fn(ensure!(x > y)));
-    env.define("<".into(), Expr::Fn(ensure!(x < y)));
-    env.define(">=".into(), Expr::Fn(ensure!(x >= y)));
-    env.define("<=".into(), Expr::Fn(ensure!(x <= y)));
+    // Numbers are ordered by value, strings and characters lexicographically.
+    fn order(x: &Expr, y: &Expr) -> LangResult<Option<Ordering>> {
+        match (x, y) {
+            (Expr::Num(x), Expr::Num(y)) => Ok(x.partial_cmp(y)),
+            (Expr::String(x), Expr::String(y)) => Ok(x.partial_cmp(y)),
+            (Expr::Char(x), Expr::Char(y)) => Ok(x.partial_cmp(y)),
+            _ => Err(LangError::Other(format!(
+                "can't compare {} with {}",
+                Written(x),
+                Written(y)
+            ))),
+        }
+    }
+
+    env.define(">".into(), Expr::Fn(ensure!(is_gt)));
+    env.define("<".into(), Expr::Fn(ensure!(is_lt)));
+    env.define(">=".into(), Expr::Fn(ensure!(is_ge)));
+    env.define("<=".into(), Expr::Fn(ensure!(is_le)));
 }
 
+// See `Expr::is_eq`, `Expr::is_eqv` and the `PartialEq` of `Expr` for the semantics.
 fn define_equal(env: &mut Env) {
-    // let mut env = env.borrow_mut();
-    env.define("=".into(), Expr::Fn(|args| ensure(args, |x, y| x == y)));
-    env.define("!=".into(), Expr::Fn(|args| ensure(args, |x, y| x != y)));
+    macro_rules! equal {
+        ($a:expr => $method:ident) => {
+            env.define(
+                $a.into(),
+                Expr::Fn(|args| match args {
+                    [x, y] => Ok(Expr::Bool(x.$method(y))),
+                    _ => Err(LangError::InvalidArgsLen),
+                }),
+            );
+        };
+    }
+
+    // `=` only accepts numbers and compares them by value, so `(= 1 1.0)` holds.
+    // `!=` holds when no two adjacent numbers are equal.
+    fn numbers_equal(args: &[Expr], equal: bool) -> LangResult<Expr> {
+        if args.len() < 2 {
+            return Err(LangError::InvalidArgsLen);
+        }
+        let mut result = true;
+        for (idx, pair) in args.windows(2).enumerate() {
+            match (&pair[0], &pair[1]) {
+                (Expr::Num(x), Expr::Num(y)) => result &= (x == y) == equal,
+                (Expr::Num(_), y) => return Err(expected("a number", idx + 1, y)),
+                (x, _) => return Err(expected("a number", idx, x)),
+            }
+        }
+        Ok(Expr::Bool(result))
+    }
+
+    equal!("eq?" => is_eq);
+    equal!("eqv?" => is_eqv);
+    equal!("equal?" => eq);
+
+    env.define("=".into(), Expr::Fn(|args| numbers_equal(args, true)));
+    env.define("!=".into(), Expr::Fn(|args| numbers_equal(args, false)));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run;
+
+    #[test]
+    fn division_is_exact() {
+        assert_eq!(run("(/ 6 4)").unwrap(), "3/2");
+        assert_eq!(run("(/ 6 3)").unwrap(), "2");
+        assert_eq!(run("(/ 1 2 2)").unwrap(), "1/4");
+        assert_eq!(run("(/ 5)").unwrap(), "1/5");
+        assert_eq!(run("(/ 1/5)").unwrap(), "5");
+        assert_eq!(run("(/ 4.0)").unwrap(), "0.25");
+        assert!(run("(/ 0)").is_err());
+        assert!(run("(/)").is_err());
+    }
+
+    #[test]
+    fn hash_tables() {
+        let table = "(define t (make-hash-table))
+            (hash-table-set! t '(1 2) 'list)
+            (hash-table-set! t \"key\" 1)";
+        let run = |code: &str| run(&format!("{table} {code}"));
+        // Keys are compared with `equal?`.
+        assert_eq!(run("(hash-table-ref t (list 1 2))").unwrap(), "list");
+        assert_eq!(run("(hash-table-ref t 'x (lambda () 0))").unwrap(), "0");
+        assert!(run("(hash-table-ref t 'x)").is_err());
+        assert_eq!(
+            run("(hash-table-update! t \"key\" (lambda (x) (+ x 1))) (hash-table-ref t \"key\")")
+                .unwrap(),
+            "2"
+        );
+        assert_eq!(
+            run("(hash-table-update! t 'n (lambda (x) (+ x 1)) (lambda () 0)) (hash-table-ref t 'n)")
+                .unwrap(),
+            "1"
+        );
+        assert_eq!(
+            run("(hash-table-delete! t '(1 2)) (hash-table->alist t)").unwrap(),
+            "((\"key\" . 1))"
+        );
+        assert_eq!(
+            run("(hash-table-delete! t '(1 2)) (hash-table-keys t)").unwrap(),
+            "(\"key\")"
+        );
+    }
 }
@@ -1,115 +1,508 @@
-use crate::{ast::Expr, builtin};
-use lru::LruCache;
-use std::{cell::RefCell, collections::BTreeMap, num::NonZeroUsize, rc::Rc};
+use crate::{ast::Expr, builtin, symbol::Symbol, LangError, LangResult};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Debug,
+    rc::{Rc, Weak},
+};
 
-const CACHE_NUM: usize = 20;
+// Keyed by interned symbols, so a lookup hashes an id instead of a name.
+pub type Map = HashMap<Symbol, Expr>;
 
-pub type SymbolName = String;
-pub type Map = BTreeMap<SymbolName, Expr>;
+// Bindings of a local scope, e.g. the parameters of a lambda call.
+// They are usually only a few, so a linear scan beats any map here.
+pub type Frame = Vec<(Symbol, Expr)>;
 
-// NOTE/FIXME
-// The Enviroment/Nested-Scope implement has problem now.
-// The cache here is just for improvement performance in recursive lambda.
+// NOTE:
+// Scopes form a chain from the innermost scope to the global one.
+// A flat `Vec<Map>` indexed by the current scope doesn't work here,
+// because a lambda may outlive the call that created its scope,
+// so every scope is reference counted and captured by the lambdas defined in it.
 //
-// Future code:
+// Lookup walks the chain from the innermost scope outwards,
+// so an inner binding always shadows an outer one with the same name.
 //
-/*
-    #[derive(Debug, Clone)]
-    pub struct Env {
-        current_scope: usize,
-        local: Vec<Map>,
-        // local_cache: LruCache   // <-- ?
-    }
-
-    pub fn get(&self, key: &SymbolName) -> Option<Expr> {
-        get_inner(self.current_scope, key)
-    }
-
-    fn get_inner(&self, scope: usize, key: &SymbolName) -> Option<Expr> {
-        let local = &self.local[scope];
-        if let Some(expr) = local.get(key) {
-            Some(expr)
-        } else if scope > 0 {
-            get_inner(scope - 1, key)
-        } else {
-            None
-        }
-    }
-
-    pub define() { /*  */ }
-    pub undefine() {/* */ }
-    pub extend() { /* */ }
-*/
+// A macro expansion is evaluated in a scope which remembers the expansion
+// and the environment where the macro is defined.
+// A symbol renamed by that expansion which isn't bound by the expansion itself
+// is looked up by its original name in the macro's environment,
+// so a template refers to the bindings visible where the macro is defined.
+//
+// A scope which binds a lambda or a macro defined in it owns itself,
+// e.g. an internal `define` of a procedure, `letrec` or a local `define-syntax`.
+// Reference counting alone never frees such a cycle, so every local scope
+// is registered and `collect` frees the cycles which nothing else refers to.
+// Named let doesn't create a cycle in the first place, see `Lambda::name`.
+#[derive(Clone)]
+pub struct Env(Rc<Scope>);
 
-// Shit code here, but I have no time to fix :(
-// I will fix it in next year(2026)
-#[derive(Debug, Clone)]
-pub struct Env {
-    parent: Option<Rc<RefCell<Env>>>,
-    local: Map,
-    pub cache: Rc<RefCell<LruCache<SymbolName, Expr>>>,
+enum Scope {
+    Global(RefCell<Map>),
+    Local {
+        frame: RefCell<Frame>,
+        parent: Env,
+    },
+    // Binds nothing, definitions go to the parent.
+    Macro {
+        expansion: u32,
+        env: Env,
+        parent: Env,
+    },
 }
 
 impl Default for Env {
     fn default() -> Self {
-        let cache = LruCache::new(NonZeroUsize::new(CACHE_NUM).unwrap());
-        let cache = Rc::new(RefCell::new(cache));
-        let cache = cache;
-        let mut env = Self {
-            parent: None,
-            local: Map::new(),
-            cache,
-        };
+        let mut env = Self(Rc::new(Scope::Global(RefCell::new(Map::new()))));
         builtin::define_std(&mut env);
         env
     }
 }
 
+// Scopes may contain lambdas which capture the scope itself,
+// so neither printing nor comparing them can recurse into the bindings.
+impl Debug for Env {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Env").finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Env {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
 impl Env {
-    pub fn new(
-        local: Map,
-        parent: Option<Rc<RefCell<Env>>>,
-        cache: Rc<RefCell<LruCache<SymbolName, Expr>>>,
-    ) -> Self {
-        Self {
-            local,
+    // Creates a new scope whose parent is `self`.
+    pub fn extend(&self, frame: Frame) -> Self {
+        let frame = RefCell::new(frame);
+        let parent = self.clone();
+        Self::register(Scope::Local { frame, parent })
+    }
+
+    // Creates the scope where the expansion `expansion` of a macro
+    // defined in `env` is evaluated.
+    pub fn expand(&self, env: &Env, expansion: u32) -> Self {
+        let env = env.clone();
+        let parent = self.clone();
+        Self::register(Scope::Macro {
+            expansion,
+            env,
             parent,
-            cache,
+        })
+    }
+
+    fn register(scope: Scope) -> Self {
+        let scope = Rc::new(scope);
+        let full = COLLECTOR.with_borrow_mut(|collector| {
+            collector.scopes.push(Rc::downgrade(&scope));
+            collector.scopes.len() > collector.threshold
+        });
+        if full {
+            collect();
+        }
+        Self(scope)
+    }
+
+    // The environment of the macro whose expansion renamed `symbol`.
+    fn macro_env(&self, symbol: Symbol) -> Option<&Env> {
+        let mut env = self;
+        loop {
+            match env.0.as_ref() {
+                Scope::Global(_) => return None,
+                Scope::Local { parent, .. } => env = parent,
+                Scope::Macro {
+                    expansion,
+                    env: macro_env,
+                    parent,
+                } => match *expansion == symbol.expansion() {
+                    true => return Some(macro_env),
+                    false => env = parent,
+                },
+            }
+        }
+    }
+
+    // Always binds in the current scope, shadowing any outer binding.
+    pub fn define(&mut self, symbol: Symbol, value: Expr) {
+        match self.0.as_ref() {
+            Scope::Macro { parent, .. } => parent.clone().define(symbol, value),
+            Scope::Global(map) => {
+                map.borrow_mut().insert(symbol, value);
+            }
+            Scope::Local { frame, .. } => {
+                let mut frame = frame.borrow_mut();
+                match frame.iter_mut().find(|(name, _)| *name == symbol) {
+                    Some((_, old)) => *old = value,
+                    None => frame.push((symbol, value)),
+                }
+            }
+        }
+    }
+
+    // Rebinds `symbol` in the scope where it is defined,
+    // so every lambda capturing that scope sees the new value.
+    pub fn set(&self, symbol: Symbol, value: Expr) -> LangResult<()> {
+        let Some(original) = symbol.original() else {
+            return self.set_exact(symbol, value);
+        };
+        if self.get_exact(symbol).is_some() {
+            return self.set_exact(symbol, value);
+        }
+        match self.macro_env(symbol) {
+            Some(env) => env.set(original, value),
+            None => self.set(original, value),
+        }
+    }
+
+    fn set_exact(&self, symbol: Symbol, value: Expr) -> LangResult<()> {
+        let mut env = self;
+        loop {
+            match env.0.as_ref() {
+                Scope::Global(map) => {
+                    let mut map = map.borrow_mut();
+                    let old = map
+                        .get_mut(&symbol)
+                        .ok_or_else(|| LangError::InvalidSymbol(symbol.to_string()))?;
+                    *old = value;
+                    return Ok(());
+                }
+                Scope::Local { frame, parent } => {
+                    let mut frame = frame.borrow_mut();
+                    match frame.iter_mut().find(|(name, _)| *name == symbol) {
+                        Some((_, old)) => {
+                            *old = value;
+                            return Ok(());
+                        }
+                        None => env = parent,
+                    }
+                }
+                Scope::Macro { parent, .. } => env = parent,
+            }
+        }
+    }
+
+    pub fn undefine(&self, symbol: Symbol) {
+        let mut env = self;
+        loop {
+            match env.0.as_ref() {
+                Scope::Global(map) => {
+                    map.borrow_mut().remove(&symbol);
+                    return;
+                }
+                Scope::Local { frame, parent } => {
+                    let mut frame = frame.borrow_mut();
+                    match frame.iter().position(|(name, _)| *name == symbol) {
+                        Some(idx) => {
+                            frame.remove(idx);
+                            return;
+                        }
+                        None => env = parent,
+                    }
+                }
+                Scope::Macro { parent, .. } => env = parent,
+            }
+        }
+    }
+
+    pub fn get(&self, symbol: Symbol) -> Option<Expr> {
+        self.get_exact(symbol).or_else(|| {
+            let original = symbol.original()?;
+            match self.macro_env(symbol) {
+                Some(env) => env.get(original),
+                None => self.get(original),
+            }
+        })
+    }
+
+    fn get_exact(&self, symbol: Symbol) -> Option<Expr> {
+        let mut env = self;
+        loop {
+            match env.0.as_ref() {
+                Scope::Global(map) => return map.borrow().get(&symbol).cloned(),
+                Scope::Local { frame, parent } => {
+                    let frame = frame.borrow();
+                    match frame.iter().find(|(name, _)| *name == symbol) {
+                        Some((_, value)) => return Some(value.clone()),
+                        None => env = parent,
+                    }
+                }
+                Scope::Macro { parent, .. } => env = parent,
+            }
         }
     }
+}
+
+// NOTE:
+// The collector uses trial deletion. A reference to a scope counted by `Rc`
+// which isn't found in another registered scope is held from elsewhere,
+// e.g. by the evaluator on the stack or by the global scope,
+// so that scope and every scope reachable from it are alive.
+// The bindings of the remaining scopes are dropped, which breaks their cycles.
+//
+// Values which are shared, e.g. a list bound in two scopes, aren't searched,
+// the scopes they refer to are kept alive, so the collector never frees
+// a scope which is still used but may keep a garbage one until it isn't shared.
+//
+// Every local and macro scope, some of them may be freed already.
+struct Collector {
+    scopes: Vec<Weak<Scope>>,
+    // Collects when there are more scopes than this,
+    // so a collection costs about as much as registering the scopes since the last one.
+    threshold: usize,
+}
 
-    // pub fn extend(parent: Env) -> Self {
-    //     Self {
-    //         parent: Some(parent),
-    //         ..Self::default()
-    //     }
-    // }
+const MIN_THRESHOLD: usize = 1024;
+
+thread_local! {
+    static COLLECTOR: RefCell<Collector> = const {
+        RefCell::new(Collector {
+            scopes: Vec::new(),
+            threshold: MIN_THRESHOLD,
+        })
+    };
+}
 
-    pub fn define(&mut self, symbol: SymbolName, value: Expr) {
-        let mut cache = self.cache.borrow_mut();
-        cache.put(symbol.clone(), value.clone());
-        self.local.insert(symbol, value);
-        // }
+// Frees the scopes which only refer to each other, returns how many are left.
+pub fn collect() -> usize {
+    let scopes: Vec<Rc<Scope>> = COLLECTOR.with_borrow_mut(|collector| {
+        let scopes = std::mem::take(&mut collector.scopes);
+        scopes.iter().filter_map(Weak::upgrade).collect()
+    });
+    let index: HashMap<*const Scope, usize> = scopes
+        .iter()
+        .enumerate()
+        .map(|(idx, scope)| (Rc::as_ptr(scope), idx))
+        .collect();
+
+    // The references which aren't held by `scopes` itself.
+    let mut counts: Vec<usize> = scopes.iter().map(|s| Rc::strong_count(s) - 1).collect();
+    let mut alive = vec![false; scopes.len()];
+    let mut edges = vec![vec![]; scopes.len()];
+    let mut work = 0;
+    for (idx, scope) in scopes.iter().enumerate() {
+        let mut visit = |env: &Env| {
+            if let Some(&target) = index.get(&Rc::as_ptr(&env.0)) {
+                edges[idx].push(target);
+            }
+        };
+        match scope.as_ref() {
+            Scope::Global(_) => {}
+            Scope::Local { frame, parent } => {
+                visit(parent);
+                match frame.try_borrow() {
+                    Ok(frame) => frame
+                        .iter()
+                        .for_each(|(_, value)| scopes_in(value, &mut visit, &mut work)),
+                    // Being changed right now, so it's in use.
+                    Err(_) => alive[idx] = true,
+                }
+            }
+            Scope::Macro { env, parent, .. } => {
+                visit(env);
+                visit(parent);
+            }
+        }
+        for &target in &edges[idx] {
+            counts[target] -= 1;
+        }
     }
 
-    pub fn undefine(&mut self, symbol: &SymbolName) {
-        if self.local.contains_key(symbol) {
-            self.local.remove(symbol);
-        } else if let Some(parent) = &self.parent {
-            parent.borrow_mut().undefine(symbol);
+    // Everything reachable from a scope referred to from elsewhere is alive.
+    let mut stack = vec![];
+    for idx in 0..scopes.len() {
+        if alive[idx] || counts[idx] > 0 {
+            alive[idx] = true;
+            stack.push(idx);
         }
     }
-    pub fn get(&self, symbol: &SymbolName) -> Option<Expr> {
-        if let Some(expr) = self.cache.borrow_mut().get(symbol) {
-            return Some(expr.clone());
+    while let Some(idx) = stack.pop() {
+        for &target in &edges[idx] {
+            if !alive[target] {
+                alive[target] = true;
+                stack.push(target);
+            }
         }
+    }
 
-        match self.local.get(symbol) {
-            Some(value) => Some(value.clone()),
-            None => match &self.parent {
-                None => None,
-                Some(parent) => parent.borrow_mut().get(symbol),
-            },
+    // Dropped after the collector is released, dropping them frees other scopes.
+    let mut garbage = vec![];
+    let live = COLLECTOR.with_borrow_mut(|collector| {
+        for (scope, alive) in scopes.iter().zip(alive) {
+            match (alive, scope.as_ref()) {
+                (true, _) => collector.scopes.push(Rc::downgrade(scope)),
+                (false, Scope::Local { frame, .. }) => {
+                    if let Ok(mut frame) = frame.try_borrow_mut() {
+                        garbage.push(std::mem::take(&mut *frame));
+                    }
+                }
+                (false, _) => {}
+            }
+        }
+        let live = collector.scopes.len();
+        collector.threshold = MIN_THRESHOLD.max(2 * live + work);
+        live
+    });
+    drop(scopes);
+    drop(garbage);
+    live
+}
+
+// Calls `visit` for every scope `value` refers to,
+// searching only values which aren't shared with anything else.
+// Counts the values searched in `work`.
+fn scopes_in(value: &Expr, visit: &mut impl FnMut(&Env), work: &mut usize) {
+    *work += 1;
+    match value {
+        Expr::Lambda(lambda) => visit(&lambda.env),
+        Expr::Macro(m) if Rc::strong_count(m) == 1 => visit(&m.env),
+        Expr::SExpr(items) if Rc::strong_count(items) == 1 => {
+            items.iter().for_each(|item| scopes_in(item, visit, work))
+        }
+        Expr::Vector(items) if Rc::strong_count(items) == 1 => {
+            if let Ok(items) = items.try_borrow() {
+                items.iter().for_each(|item| scopes_in(item, visit, work));
+            }
         }
+        Expr::HashTable(table) if Rc::strong_count(table) == 1 => {
+            if let Ok(table) = table.try_borrow() {
+                for (key, value) in table.iter() {
+                    scopes_in(key, visit, work);
+                    scopes_in(value, visit, work);
+                }
+            }
+        }
+        Expr::Map(map) if Rc::strong_count(map) == 1 => {
+            for (key, value) in map.iter() {
+                scopes_in(key, visit, work);
+                scopes_in(value, visit, work);
+            }
+        }
+        Expr::Set(set) if Rc::strong_count(set) == 1 => {
+            set.iter().for_each(|item| scopes_in(item, visit, work))
+        }
+        Expr::Record(record) if Rc::strong_count(record) == 1 => {
+            if let Ok(values) = record.values.try_borrow() {
+                values
+                    .iter()
+                    .for_each(|value| scopes_in(value, visit, work));
+            }
+        }
+        // Iterates over the tail instead of recursing, lists may be long.
+        Expr::Pair(pair) if Rc::strong_count(pair) == 1 => {
+            let mut pair = pair.clone();
+            loop {
+                if let Ok(car) = pair.car.try_borrow() {
+                    scopes_in(&car, visit, work);
+                }
+                let next = match pair.cdr.try_borrow() {
+                    Ok(cdr) => match &*cdr {
+                        Expr::Pair(next) if Rc::strong_count(next) == 1 => Some(next.clone()),
+                        cdr => {
+                            scopes_in(cdr, visit, work);
+                            None
+                        }
+                    },
+                    Err(_) => None,
+                };
+                match next {
+                    Some(next) => pair = next,
+                    None => break,
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{COLLECTOR, MIN_THRESHOLD};
+    use crate::run;
+
+    // The scopes which aren't freed yet, without collecting them.
+    fn scopes() -> usize {
+        COLLECTOR.with_borrow(|collector| {
+            let scopes = collector.scopes.iter();
+            scopes.filter(|scope| scope.strong_count() > 0).count()
+        })
+    }
+
+    // Each call leaves a scope which owns itself, these must be freed while looping.
+    fn stays_bounded(procedure: &str) {
+        let code = format!(
+            "{procedure}
+            (define (loop n) (if (= n 0) 'done (begin (f n) (loop (- n 1)))))
+            (loop 20000)"
+        );
+        assert_eq!(run(&code).unwrap(), "done");
+        assert!(scopes() < 4 * MIN_THRESHOLD);
+    }
+
+    #[test]
+    fn inner_bindings_shadow_outer_ones() {
+        assert_eq!(run("(define n 1) (define (f n) n) (f 2)").unwrap(), "2");
+        assert_eq!(run("(define n 1) (define (f n) n) (f 2) n").unwrap(), "1");
+        assert_eq!(run("(define (f n) (let ((n 3)) n)) (f 2)").unwrap(), "3");
+        assert_eq!(
+            run("(define (f n) (let ((n (+ n 1))) n) n) (f 2)").unwrap(),
+            "2"
+        );
+        // A global defined after the lambda doesn't shadow its parameter either.
+        assert_eq!(
+            run("(define (f n) (lambda () n)) (define g (f 2)) (define n 1) (g)").unwrap(),
+            "2"
+        );
+        assert!(run("(define (f n) n) (f 2) n").is_err());
+    }
+
+    #[test]
+    fn set_rebinds_in_the_defining_scope() {
+        assert_eq!(run("(define x 1) (set! x 2) x").unwrap(), "2");
+        assert_eq!(
+            run("(define x 1) (define (f) (set! x 2)) (f) x").unwrap(),
+            "2"
+        );
+        // Only the innermost binding is changed.
+        assert_eq!(
+            run("(define x 1) (define (f x) (set! x 3) x) (list (f 2) x)").unwrap(),
+            "(3 1)"
+        );
+        let make_counter = "(define (make-counter)
+              (let ((n 0)) (lambda () (set! n (+ n 1)) n)))";
+        assert_eq!(
+            run(&format!(
+                "{make_counter} (define a (make-counter)) (define b (make-counter))
+                (a) (a) (b) (list (a) (b))"
+            ))
+            .unwrap(),
+            "(3 2)"
+        );
+        assert!(run("(set! undefined 1)").is_err());
+        assert!(run("(define (f) (define x 1) x) (f) (set! x 2)").is_err());
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        stays_bounded("(define (f x) (define (g y) (+ x y)) (g 1))");
+        stays_bounded("(define (f x) (letrec ((g (lambda (y) (+ x y)))) (g 1)))");
+        stays_bounded("(define (f x) (letrec* ((g (lambda (y) (+ x y)))) (g 1)))");
+        stays_bounded(
+            "(define (f x)
+              (define-syntax add (syntax-rules () ((_ y) (+ x y))))
+              (add 1))",
+        );
+    }
+
+    #[test]
+    fn referred_scopes_are_kept() {
+        let code = "(define (counter)
+              (define n 0)
+              (define (next) (set! n (+ n 1)) n)
+              next)
+            (define c (counter))
+            (define (loop n) (if (= n 0) (c) (begin (counter) (c) (loop (- n 1)))))
+            (loop 5000)";
+        assert_eq!(run(code).unwrap(), "5001");
     }
 }
@@ -1,9 +1,12 @@
 use std::rc::Rc;
 
 use crate::{
-    ast::{Expr, Lambda},
-    env::Env,
-    utils::child_env_for_lambda,
+    ast::{Expr, Lambda, Written},
+    env::{Env, Frame},
+    macros::Macro,
+    record::define_record_type,
+    symbol::Symbol,
+    utils::{child_env_for_lambda, parse_list_of_args, parse_list_of_bindings},
     LangError, LangResult,
 };
 
@@ -12,22 +15,79 @@ use crate::{
 // so many `clone()`.
 // should improve performance.
 
+// The result of evaluating one s-expression.
+// Expressions in tail position are handed back to the loop in `eval_sexpr`
+// instead of being evaluated recursively, so tail calls don't grow the stack.
+enum Step {
+    Done(Expr),
+    Tail(Rc<[Expr]>, Env),
+}
+
 pub fn eval(expr: &Expr, env: &mut Env) -> LangResult<Expr> {
     match expr {
-        Expr::Symbol(s) => env.get(s).ok_or(LangError::InvalidSymbol(s.into())),
-        Expr::Num(_) | Expr::Bool(_) | Expr::String(_) | Expr::Nil | Expr::QExpr(_) => {
-            Ok(expr.clone())
-        }
-        Expr::SExpr(s_expr) => eval_sexpr(s_expr, env),
+        Expr::Symbol(s) => lookup(*s, env),
+        Expr::Num(_)
+        | Expr::Bool(_)
+        | Expr::String(_)
+        | Expr::Char(_)
+        | Expr::HashTable(_)
+        | Expr::Map(_)
+        | Expr::Set(_)
+        | Expr::Record(_)
+        | Expr::RecordProc(_)
+        | Expr::Nil => Ok(expr.clone()),
+        // A literal evaluates to a new vector every time, like a quoted list.
+        Expr::Vector(_) => Ok(expr.to_datum()),
+        Expr::SExpr(s_expr) => eval_sexpr(s_expr.clone(), env.clone()),
+        Expr::Pair(_) => Err(LangError::Other(format!(
+            "can't evaluate improper list: {}",
+            Written(expr)
+        ))),
         _ => unimplemented!(),
     }
 }
 
-pub fn eval_sexpr(s_expr: &[Expr], env: &mut Env) -> LangResult<Expr> {
+fn lookup(symbol: Symbol, env: &Env) -> LangResult<Expr> {
+    env.get(symbol)
+        .ok_or_else(|| LangError::InvalidSymbol(symbol.to_string()))
+}
+
+pub fn eval_sexpr(mut s_expr: Rc<[Expr]>, mut env: Env) -> LangResult<Expr> {
+    loop {
+        match eval_step(&s_expr, &mut env)? {
+            Step::Done(expr) => return Ok(expr),
+            Step::Tail(next, next_env) => {
+                s_expr = next;
+                env = next_env;
+            }
+        }
+    }
+}
+
+// Evaluates a sequence of expressions, the last one in tail position.
+// Internal `define`s bind in the current scope one after another,
+// so they behave like `letrec*`.
+fn eval_body(body: &[Expr], env: &mut Env) -> LangResult<Step> {
+    let (last, init) = body.split_last().ok_or(LangError::InvalidArgsLen)?;
+    for expr in init {
+        eval(expr, env)?;
+    }
+    eval_tail(last, env)
+}
+
+// Evaluates `expr` in tail position.
+fn eval_tail(expr: &Expr, env: &mut Env) -> LangResult<Step> {
+    match expr {
+        Expr::SExpr(s_expr) => Ok(Step::Tail(s_expr.clone(), env.clone())),
+        expr => eval(expr, env).map(Step::Done),
+    }
+}
+
+fn eval_step(s_expr: &[Expr], env: &mut Env) -> LangResult<Step> {
     let len = s_expr.len();
 
     if len == 0 {
-        return Ok(Expr::Nil);
+        return Ok(Step::Done(Expr::Nil));
     }
 
     let first = s_expr.first().unwrap();
@@ -38,88 +98,294 @@ pub fn eval_sexpr(s_expr: &[Expr], env: &mut Env) -> LangResult<Expr> {
     }
     let first_eval = eval(first, env)?;
     match first_eval {
-        Expr::Fn(f) => f(&eval_args(args, env)?),
+        Expr::Fn(f) => f(&eval_args(args, env)?).map(Step::Done),
+        Expr::RecordProc(proc) => proc.call(&eval_args(args, env)?).map(Step::Done),
         Expr::Lambda(lambda) => {
-            let mut child_env = child_env_for_lambda(lambda.params, args, env)?;
-            eval(&lambda.body, &mut child_env)
+            let mut child_env = child_env_for_lambda(&lambda, args, env)?;
+            eval_body(&lambda.body, &mut child_env)
+        }
+        Expr::Macro(m) => {
+            let expansion = Symbol::next_expansion();
+            let expr = m.expand(s_expr, expansion)?;
+            eval_tail(&expr, &mut env.expand(&m.env, expansion))
         }
-        expr if len == 1 => eval(&expr, env),
+        expr if len == 1 => eval(&expr, env).map(Step::Done),
         expr => Err(LangError::InvalidSymbol(expr.to_string())),
     }
 }
 
+// Calls a procedure with arguments which are already evaluated,
+// for builtins taking procedures like `vector-map`.
+pub fn apply(proc: &Expr, args: Vec<Expr>) -> LangResult<Expr> {
+    match proc {
+        Expr::Fn(f) => f(&args),
+        Expr::RecordProc(proc) => proc.call(&args),
+        Expr::Lambda(lambda) => match eval_body(&lambda.body, &mut lambda.call_env(args)?)? {
+            Step::Done(expr) => Ok(expr),
+            Step::Tail(s_expr, env) => eval_sexpr(s_expr, env),
+        },
+        _ => Err(LangError::Other(format!(
+            "not a procedure: {}",
+            Written(proc)
+        ))),
+    }
+}
+
 pub fn eval_args(args: &[Expr], env: &mut Env) -> LangResult<Vec<Expr>> {
     args.iter().map(|x| eval(x, env)).collect()
 }
 
-fn eval_keyword(expr: &Expr, args: &[Expr], env: &mut Env) -> Option<LangResult<Expr>> {
-    match expr {
-        Expr::Symbol(s) => match s.as_str() {
-            "if" => Some(eval_if(args, env)),
-            "cond" => Some(eval_cond(args, env)),
-            "define" => Some(eval_def(args, env)),
-            "lambda" => Some(eval_lambda(args, env)),
-            _ => None,
-        },
-        _ => None,
+type SpecialForm = fn(&[Expr], &mut Env) -> LangResult<Step>;
+
+// Forms whose arguments aren't evaluated like those of a procedure.
+// Both `eval_keyword` and `is_keyword` are driven by this list.
+const SPECIAL_FORMS: &[(&str, SpecialForm)] = &[
+    ("if", eval_if),
+    ("begin", eval_body),
+    ("and", eval_and),
+    ("or", eval_or),
+    ("when", |args, env| eval_when(args, env, true)),
+    ("unless", |args, env| eval_when(args, env, false)),
+    ("cond", eval_cond),
+    ("define", |args, env| eval_def(args, env).map(Step::Done)),
+    ("set!", |args, env| eval_set(args, env).map(Step::Done)),
+    ("lambda", |args, env| eval_lambda(args, env).map(Step::Done)),
+    ("let", eval_let),
+    ("let*", eval_let_star),
+    ("letrec", eval_letrec),
+    ("letrec*", eval_letrec_star),
+    ("quote", |args, _| eval_quote(args).map(Step::Done)),
+    ("quasiquote", |args, env| {
+        eval_quasiquote(args, env).map(Step::Done)
+    }),
+    ("define-syntax", |args, env| {
+        eval_define_syntax(args, env).map(Step::Done)
+    }),
+    ("define-record-type", |args, env| {
+        define_record_type(args, env).map(Step::Done)
+    }),
+    ("let-syntax", eval_let_syntax),
+    ("letrec-syntax", eval_let_syntax),
+    ("macroexpand", |args, env| {
+        eval_macroexpand(args, env, true).map(Step::Done)
+    }),
+    ("macroexpand-1", |args, env| {
+        eval_macroexpand(args, env, false).map(Step::Done)
+    }),
+];
+
+// Symbols which only mean something inside a special form.
+const AUXILIARY_KEYWORDS: &[&str] = &["else", "unquote", "unquote-splicing"];
+
+fn eval_keyword(expr: &Expr, args: &[Expr], env: &mut Env) -> Option<LangResult<Step>> {
+    let Expr::Symbol(s) = expr else {
+        return None;
+    };
+    if s.original().is_some() {
+        return None;
     }
+    let name = s.as_str();
+    let (_, form) = SPECIAL_FORMS.iter().find(|(keyword, _)| *keyword == name)?;
+    Some(form(args, env))
 }
 
-fn eval_cond(args: &[Expr], env: &mut Env) -> Result<Expr, LangError> {
-    if args.len() < 2 {
+// Whether `symbol` is handled by `eval_keyword` instead of the environment,
+// macros never rename these symbols.
+pub fn is_keyword(symbol: &str) -> bool {
+    SPECIAL_FORMS.iter().any(|(keyword, _)| *keyword == symbol)
+        || AUXILIARY_KEYWORDS.contains(&symbol)
+}
+
+// (cond (test body ...) ... (else body ...))
+// A clause without a body returns the value of its test, `nil` when no clause matches.
+fn eval_cond(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.is_empty() {
         return Err(LangError::InvalidArgsLen);
     }
-    for cond in args {
-        match cond {
-            Expr::SExpr(s_expr) => {
-                // println!("{s_expr:?}");
-                if s_expr.len() != 2 {
-                    return Err(LangError::InvalidArgsLen);
-                }
-                if let Expr::Symbol(s) = &s_expr[0] {
-                    if s == "else" {
-                        return eval(&s_expr[1], env);
-                    }
-                }
-                match eval(&s_expr[0], env)? {
-                    Expr::Bool(true) => return eval(&s_expr[1], env),
-                    Expr::Bool(false) => continue,
-                    _ => return Err(LangError::TypeMismatched),
-                }
-            }
-            _ => return Err(LangError::FuckYou),
+    for clause in args {
+        let Expr::SExpr(clause) = clause else {
+            return Err(LangError::Other(format!(
+                "expected a clause like (test body ...): {}",
+                Written(clause)
+            )));
+        };
+        let Some((test, body)) = clause.split_first() else {
+            return Err(LangError::InvalidArgsLen);
+        };
+        if matches!(test, Expr::Symbol(s) if s == "else") {
+            return eval_body(body, env);
+        }
+        let value = eval(test, env)?;
+        if value.is_truthy() {
+            return match body.is_empty() {
+                true => Ok(Step::Done(value)),
+                false => eval_body(body, env),
+            };
         }
     }
-    let other = args.last().unwrap();
-    eval(other, env)
+    Ok(Step::Done(Expr::Nil))
 }
 
-fn eval_lambda(args: &[Expr], _env: &Env) -> Result<Expr, LangError> {
-    if args.len() != 2 {
+// (lambda (params ...) body ...)
+fn eval_lambda(args: &[Expr], env: &Env) -> Result<Expr, LangError> {
+    if args.len() < 2 {
         return Err(LangError::InvalidArgsLen);
     }
 
+    let (params, rest) = parse_list_of_args(&args[0])?;
     Ok(Expr::Lambda(Lambda {
-        params: Rc::new(args.first().unwrap().clone()),
-        body: Rc::new(args.get(1).unwrap().clone()),
+        params: params.into(),
+        rest,
+        body: args[1..].into(),
+        env: env.clone(),
+        name: None,
     }))
 }
 
-fn eval_if(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
+// (let ((name value) ...) body ...)
+// (let loop ((name value) ...) body ...)
+fn eval_let(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if let Some(Expr::Symbol(_)) = args.first() {
+        return eval_named_let(args, env);
+    }
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let frame = parse_list_of_bindings(&args[0])?
+        .into_iter()
+        .map(|(symbol, value)| Ok((symbol, eval(value, env)?)))
+        .collect::<LangResult<_>>()?;
+    eval_body(&args[1..], &mut env.extend(frame))
+}
+
+// The loop is a lambda which binds itself to `name` in every call,
+// so it is visible in the body but not in the initial values.
+fn eval_named_let(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.len() < 3 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let name = *args[0].inner_symbol()?;
+    let (params, values): (Vec<_>, Vec<_>) = parse_list_of_bindings(&args[1])?.into_iter().unzip();
+    let values = values
+        .into_iter()
+        .map(|value| eval(value, env))
+        .collect::<LangResult<Vec<_>>>()?;
+
+    let lambda = Lambda {
+        params: params.into(),
+        rest: None,
+        body: args[2..].into(),
+        env: env.clone(),
+        name: Some(name),
+    };
+    eval_body(&args[2..], &mut lambda.call_env(values)?)
+}
+
+// Every value is evaluated in a new scope which already has the previous bindings.
+fn eval_let_star(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let mut env = env.clone();
+    for (symbol, value) in parse_list_of_bindings(&args[0])? {
+        let value = eval(value, &mut env)?;
+        env = env.extend(vec![(symbol, value)]);
+    }
+    eval_body(&args[1..], &mut env)
+}
+
+// All values are evaluated in the new scope, then bound at once.
+fn eval_letrec(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let mut env = env.extend(Frame::new());
+    let bindings = parse_list_of_bindings(&args[0])?
+        .into_iter()
+        .map(|(symbol, value)| Ok((symbol, eval(value, &mut env)?)))
+        .collect::<LangResult<Vec<_>>>()?;
+    for (symbol, value) in bindings {
+        env.define(symbol, value);
+    }
+    eval_body(&args[1..], &mut env)
+}
+
+// Like `letrec`, but every value is bound right after it is evaluated.
+fn eval_letrec_star(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let mut env = env.extend(Frame::new());
+    for (symbol, value) in parse_list_of_bindings(&args[0])? {
+        let value = eval(value, &mut env)?;
+        env.define(symbol, value);
+    }
+    eval_body(&args[1..], &mut env)
+}
+
+fn eval_if(args: &[Expr], env: &mut Env) -> LangResult<Step> {
     if args.len() != 3 {
         return Err(LangError::InvalidArgsLen);
     }
 
     let cond = eval(&args[0], env)?;
-    let cond = cond.inner_bool()?;
 
-    let idx = if *cond { 1 } else { 2 };
+    let idx = if cond.is_truthy() { 1 } else { 2 };
     let result = &args[idx];
-    eval(result, env)
+    eval_tail(result, env)
 }
 
+// Returns the first false value, or the last value if there is none.
+fn eval_and(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    let Some((last, init)) = args.split_last() else {
+        return Ok(Step::Done(Expr::Bool(true)));
+    };
+    for expr in init {
+        let value = eval(expr, env)?;
+        if !value.is_truthy() {
+            return Ok(Step::Done(value));
+        }
+    }
+    eval_tail(last, env)
+}
+
+// Returns the first true value, or the last value if there is none.
+fn eval_or(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    let Some((last, init)) = args.split_last() else {
+        return Ok(Step::Done(Expr::Bool(false)));
+    };
+    for expr in init {
+        let value = eval(expr, env)?;
+        if value.is_truthy() {
+            return Ok(Step::Done(value));
+        }
+    }
+    eval_tail(last, env)
+}
+
+// (when test body ...)
+// (unless test body ...)
+fn eval_when(args: &[Expr], env: &mut Env, expected: bool) -> LangResult<Step> {
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    match eval(&args[0], env)?.is_truthy() == expected {
+        true => eval_body(&args[1..], env),
+        false => Ok(Step::Done(Expr::Nil)),
+    }
+}
+
+// (define name value)
+// (define (name params ...) body ...)
+// (define (name params ... . rest) body ...)
 fn eval_def(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
-    if args.len() != 2 {
+    if args.len() < 2 {
         return Err(LangError::InvalidArgsLen);
     }
 
@@ -129,20 +395,335 @@ fn eval_def(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
         }
 
         let symbol = &function[0];
-        let symbol_name = symbol.inner_symbol()?.clone();
+        let symbol_name = *symbol.inner_symbol()?;
 
         let params = &function[1..];
-        let params = Expr::SExpr(params.to_vec());
-        let body = args[1].clone();
-        let lambda = &[params, body];
-        let lambda = eval_lambda(lambda, env)?;
+        let params = Expr::SExpr(params.into());
+        let lambda = [&[params], &args[1..]].concat();
+        let lambda = eval_lambda(&lambda, env)?;
         env.define(symbol_name, lambda);
         return Ok(symbol.clone());
     }
+    if let Expr::Pair(function) = &args[0] {
+        let symbol = function.car.borrow().clone();
+        let symbol_name = *symbol.inner_symbol()?;
+
+        let params = function.cdr.borrow().clone();
+        let lambda = [&[params], &args[1..]].concat();
+        let lambda = eval_lambda(&lambda, env)?;
+        env.define(symbol_name, lambda);
+        return Ok(symbol);
+    }
+
+    if args.len() != 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
 
     let symbol = &args[0].clone();
-    let symbol_name = symbol.inner_symbol()?.clone();
+    let symbol_name = *symbol.inner_symbol()?;
     let value = eval(&args[1], env)?;
     env.define(symbol_name, value);
     Ok(symbol.clone())
 }
+
+// (set! name value)
+fn eval_set(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
+    if args.len() != 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let symbol_name = *args[0].inner_symbol()?;
+    let value = eval(&args[1], env)?;
+    env.set(symbol_name, value)?;
+    Ok(Expr::Nil)
+}
+
+// (quote datum)
+fn eval_quote(args: &[Expr]) -> LangResult<Expr> {
+    match args {
+        [datum] => Ok(datum.to_datum()),
+        _ => Err(LangError::InvalidArgsLen),
+    }
+}
+
+// (quasiquote template)
+fn eval_quasiquote(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
+    if args.len() != 1 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    quasi(&args[0], 1, env)
+}
+
+// Builds the value of a quasiquoted template.
+// The template is walked as code, the unquoted parts are evaluated as they are
+// and only the rest is converted into data, like `quote` does.
+// Only unquotes at the same nesting level as the outermost quasiquote are evaluated.
+fn quasi(template: &Expr, depth: usize, env: &mut Env) -> LangResult<Expr> {
+    match template {
+        Expr::SExpr(items) => quasi_list(items.to_vec(), None, depth, env),
+        // A dotted list like `(1 . ,x)`, its tail is a template too.
+        Expr::Pair(_) => {
+            let mut items = vec![];
+            let mut rest = template.clone();
+            while let Expr::Pair(pair) = rest {
+                items.push(pair.car.borrow().clone());
+                rest = pair.cdr.borrow().clone();
+            }
+            quasi_list(items, Some(rest), depth, env)
+        }
+        Expr::Vector(vector) => {
+            let items = vector.borrow().clone();
+            Ok(Expr::vector(
+                quasi_list(items, None, depth, env)?.list_items()?,
+            ))
+        }
+        // The items of maps and sets are data already.
+        Expr::Map(map) => {
+            let items = map
+                .iter()
+                .flat_map(|(key, value)| [key.to_syntax(), value.to_syntax()]);
+            let items = quasi_list(items.collect(), None, depth, env)?;
+            Expr::map(items.list_items()?)
+        }
+        Expr::Set(set) => {
+            let items = set.iter().map(Expr::to_syntax).collect();
+            Ok(Expr::set(
+                quasi_list(items, None, depth, env)?.list_items()?,
+            ))
+        }
+        template => Ok(template.to_datum()),
+    }
+}
+
+// Builds the list of the templates `items`, followed by the template `tail`.
+fn quasi_list(
+    mut items: Vec<Expr>,
+    mut tail: Option<Expr>,
+    depth: usize,
+    env: &mut Env,
+) -> LangResult<Expr> {
+    let keyword = |name: &str| Expr::Symbol(name.into());
+
+    if tail.is_none() {
+        match items.as_slice() {
+            [Expr::Symbol(s), x] if s == "unquote" => {
+                return match depth {
+                    1 => eval(x, env),
+                    _ => Ok(Expr::list(
+                        [keyword("unquote"), quasi(x, depth - 1, env)?],
+                        Expr::Nil,
+                    )),
+                };
+            }
+            [Expr::Symbol(s), x] if s == "quasiquote" => {
+                return Ok(Expr::list(
+                    [keyword("quasiquote"), quasi(x, depth + 1, env)?],
+                    Expr::Nil,
+                ));
+            }
+            // `(1 unquote x)` is the same list as `(1 . (unquote x))`.
+            [.., Expr::Symbol(s), _] if s == "unquote" || s == "quasiquote" => {
+                let form = items.split_off(items.len() - 2);
+                tail = Some(Expr::SExpr(form.into()));
+            }
+            _ => {}
+        }
+    }
+
+    let mut values = vec![];
+    for item in &items {
+        match special_form_arg(item, "unquote-splicing") {
+            Some(x) if depth == 1 => values.extend(eval(x, env)?.list_items()?),
+            Some(x) => values.push(Expr::list(
+                [keyword("unquote-splicing"), quasi(x, depth - 1, env)?],
+                Expr::Nil,
+            )),
+            None => values.push(quasi(item, depth, env)?),
+        }
+    }
+    let tail = match tail {
+        Some(tail) => quasi(&tail, depth, env)?,
+        None => Expr::Nil,
+    };
+    Ok(Expr::list(values, tail))
+}
+
+// Returns `x` if `form` is the s-expression `(name x)`.
+fn special_form_arg<'a>(form: &'a Expr, name: &str) -> Option<&'a Expr> {
+    match form {
+        Expr::SExpr(items) => match &items[..] {
+            [Expr::Symbol(s), x] if s == name => Some(x),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// (define-syntax name (syntax-rules (literal ...) (pattern template) ...))
+fn eval_define_syntax(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
+    if args.len() != 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let symbol_name = *args[0].inner_symbol()?;
+    let syntax = Macro::new(&args[1], env)?;
+    env.define(symbol_name, Expr::Macro(Rc::new(syntax)));
+    Ok(args[0].clone())
+}
+
+// (let-syntax ((name (syntax-rules ...)) ...) body ...)
+// The macros are defined in the scope they are bound in,
+// so they see each other and `letrec-syntax` is the same as `let-syntax` here.
+fn eval_let_syntax(args: &[Expr], env: &mut Env) -> LangResult<Step> {
+    if args.len() < 2 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let mut env = env.extend(vec![]);
+    for (symbol, syntax) in parse_list_of_bindings(&args[0])? {
+        let syntax = Macro::new(syntax, &env)?;
+        env.define(symbol, Expr::Macro(Rc::new(syntax)));
+    }
+    eval_body(&args[1..], &mut env)
+}
+
+// (macroexpand '(form ...))
+// A special form because it needs the environment to find macros,
+// but its argument is evaluated like the argument of a function.
+// `macroexpand-1` expands the form only once.
+fn eval_macroexpand(args: &[Expr], env: &mut Env, repeat: bool) -> LangResult<Expr> {
+    if args.len() != 1 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let mut form = eval(&args[0], env)?.to_syntax();
+    while let Expr::SExpr(s_expr) = &form {
+        let Some(Expr::Symbol(s)) = s_expr.first() else {
+            break;
+        };
+        let Ok(Expr::Macro(m)) = lookup(*s, env) else {
+            break;
+        };
+        form = m.expand(s_expr, Symbol::next_expansion())?;
+        if !repeat {
+            break;
+        }
+    }
+    Ok(form.to_datum())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run;
+
+    #[test]
+    fn closures_capture_their_environment() {
+        let make_adder = "(define (make-adder n) (lambda (x) (+ x n)))";
+        assert_eq!(
+            run(&format!("{make_adder} ((make-adder 2) 3)")).unwrap(),
+            "5"
+        );
+        // The caller's `n` isn't seen by the lambda.
+        assert_eq!(
+            run(&format!(
+                "{make_adder} (define add-2 (make-adder 2)) (define n 10) (add-2 3)"
+            ))
+            .unwrap(),
+            "5"
+        );
+        assert_eq!(
+            run(&format!(
+                "{make_adder} (define (call f n) (f 1)) (call (make-adder 2) 10)"
+            ))
+            .unwrap(),
+            "3"
+        );
+        // Every call of a loop closes over its own variable.
+        assert_eq!(
+            run("(define (adders n)
+                   (if (= n 0) '() (cons (lambda (x) (+ x n)) (adders (- n 1)))))
+                 (define fs (adders 3))
+                 (list ((car fs) 0) ((car (cdr fs)) 0) ((car (cdr (cdr fs))) 0))")
+            .unwrap(),
+            "(3 2 1)"
+        );
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_space() {
+        // Every tail position at once: `if`, `cond`, `begin`, `let` and the lambda body.
+        let countdown = "(define (countdown n)
+              (if (= n 0)
+                  'done
+                  (cond (#t (begin (let ((m (- n 1))) (countdown m)))))))";
+        assert_eq!(
+            run(&format!("{countdown} (countdown 1000000)")).unwrap(),
+            "done"
+        );
+        assert_eq!(
+            run("(let loop ((i 0) (sum 0)) (if (= i 1000000) sum (loop (+ i 1) (+ sum 1))))")
+                .unwrap(),
+            "1000000"
+        );
+    }
+
+    #[test]
+    fn cond_clauses() {
+        assert_eq!(run("(cond ((= 1 1) 'a))").unwrap(), "a");
+        assert_eq!(run("(cond ((= 1 2) 'a))").unwrap(), "nil");
+        assert_eq!(run("(cond ((= 1 2) 'a) ((= 1 3) 'b))").unwrap(), "nil");
+        assert_eq!(run("(cond ((= 1 2) 'a) (else 'b 'c))").unwrap(), "c");
+        assert_eq!(
+            run("(define x 0) (cond (#t (set! x 1) (+ x 1)))").unwrap(),
+            "2"
+        );
+        assert_eq!(run("(cond ((+ 1 1)) (else #f))").unwrap(), "2");
+        assert!(run("(cond)").is_err());
+        assert!(run("(cond 1)").is_err());
+    }
+
+    #[test]
+    fn rest_parameters() {
+        assert_eq!(run("(define (f . args) args) (f 1 2)").unwrap(), "(1 2)");
+        assert_eq!(run("(define (f . args) args) (f)").unwrap(), "nil");
+        assert_eq!(
+            run("(define (f x . args) (list x args)) (f 1 2 3)").unwrap(),
+            "(1 (2 3))"
+        );
+        assert!(run("(define (f x . args) x) (f)").is_err());
+        assert_eq!(run("((lambda x x) 1 2)").unwrap(), "(1 2)");
+        assert_eq!(run("((lambda (x y . z) z) 1 2 3 4)").unwrap(), "(3 4)");
+        // Exercise 2.20 of SICP.
+        let same_parity = "(define (same-parity x . xs)
+            (let loop ((xs xs))
+              (cond ((null? xs) '())
+                    ((= (remainder (car xs) 2) (remainder x 2)) (cons (car xs) (loop (cdr xs))))
+                    (else (loop (cdr xs))))))";
+        assert_eq!(
+            run(&format!(
+                "{same_parity} (cons 1 (same-parity 1 2 3 4 5 6 7))"
+            ))
+            .unwrap(),
+            "(1 3 5 7)"
+        );
+        assert_eq!(run("'(1 . (2 3))").unwrap(), "(1 2 3)");
+        assert_eq!(run("(car '((a b) . c))").unwrap(), "(a b)");
+    }
+
+    #[test]
+    fn quasiquote() {
+        assert_eq!(run("`(1 ,(+ 1 1) ,@(list 3 4))").unwrap(), "(1 2 3 4)");
+        // Unquoted parts are evaluated as code, so `()` stays an empty list.
+        assert_eq!(run("`(a ,((lambda () 1)))").unwrap(), "(a 1)");
+        assert_eq!(run("`(x ,(let () 5))").unwrap(), "(x 5)");
+        assert_eq!(run("`(1 . ,(+ 1 1))").unwrap(), "(1 . 2)");
+        assert_eq!(run("`(1 unquote (+ 1 1))").unwrap(), "(1 . 2)");
+        assert_eq!(run("`(() ,@'())").unwrap(), "(nil)");
+        assert_eq!(run("`#(1 ,(+ 1 1))").unwrap(), "#(1 2)");
+        assert_eq!(
+            run("`(1 `(2 ,(3 ,(+ 1 3))))").unwrap(),
+            "(1 (quasiquote (2 (unquote (3 4)))))"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::{
+    ast::{Expr, Written},
+    number::Number,
+    LangError, LangResult,
+};
+
+// Formats `args` like the `format` of Common Lisp.
+// Directives look like `~[width][,precision][@]directive`:
+// - `~a` displays the next argument and `~s` writes it
+// - `~d`, `~b`, `~o` and `~x` print a number in radix 10, 2, 8 and 16
+// - `~f` prints a number in fixed-point notation, `~,2f` with 2 digits after the point
+// - `~%` prints a newline and `~~` a tilde, the width repeats them
+// The width pads with spaces, numbers on the left and everything else on the right,
+// `@` pads on the other side: `~5@a` right-aligns the argument.
+pub fn format(control: &str, args: &[Expr]) -> LangResult<String> {
+    let mut output = String::new();
+    let mut args = args.iter();
+    let mut chars = control.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            output.push(c);
+            continue;
+        }
+
+        let width = read_param(&mut chars);
+        let precision = match chars.next_if_eq(&',') {
+            Some(_) => read_param(&mut chars),
+            None => None,
+        };
+        let flipped = chars.next_if_eq(&'@').is_some();
+        let Some(directive) = chars.next() else {
+            return Err(LangError::Other(
+                "format string ends in the middle of a directive".into(),
+            ));
+        };
+        let mut next_arg = || {
+            args.next()
+                .ok_or_else(|| LangError::Other(format!("no argument left for ~{directive}")))
+        };
+
+        let (text, pad_left) = match directive.to_ascii_lowercase() {
+            '%' => (String::from("\n").repeat(width.unwrap_or(1)), false),
+            '~' => (String::from("~").repeat(width.unwrap_or(1)), false),
+            'a' => (next_arg()?.to_string(), false),
+            's' => (Written(next_arg()?).to_string(), false),
+            'd' => (radix(next_arg()?, 10)?, true),
+            'b' => (radix(next_arg()?, 2)?, true),
+            'o' => (radix(next_arg()?, 8)?, true),
+            'x' => (radix(next_arg()?, 16)?, true),
+            'f' => (fixed(next_arg()?, precision)?, true),
+            _ => {
+                return Err(LangError::Other(format!(
+                    "unknown format directive: ~{directive}"
+                )))
+            }
+        };
+
+        let padding = match directive {
+            '%' | '~' => String::new(),
+            _ => " ".repeat(width.unwrap_or(0).saturating_sub(text.chars().count())),
+        };
+        match pad_left != flipped {
+            true => output.extend([padding, text]),
+            false => output.extend([text, padding]),
+        }
+    }
+
+    match args.next() {
+        None => Ok(output),
+        Some(_) => Err(LangError::Other(
+            "too many arguments for the format string".into(),
+        )),
+    }
+}
+
+fn read_param(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    digits.parse().ok()
+}
+
+fn radix(arg: &Expr, radix: u32) -> LangResult<String> {
+    number(arg)?.to_string_radix(radix)
+}
+
+fn fixed(arg: &Expr, precision: Option<usize>) -> LangResult<String> {
+    let n = number(arg)?;
+    match precision {
+        Some(precision) => Ok(format!("{:.*}", precision, n.to_f64())),
+        None => Ok(n.to_inexact().to_string()),
+    }
+}
+
+fn number(arg: &Expr) -> LangResult<&Number> {
+    match arg {
+        Expr::Num(n) => Ok(n),
+        _ => Err(LangError::Other(format!(
+            "expected a number, got {}",
+            Written(arg)
+        ))),
+    }
+}
@@ -1,4 +1,3 @@
-#![feature(iter_map_windows)]
 #![feature(iterator_try_reduce)]
 
 pub mod ast;
@@ -6,6 +5,11 @@ pub mod builtin;
 pub mod codegen;
 pub mod env;
 pub mod eval;
+pub mod format;
+pub mod macros;
+pub mod number;
+pub mod record;
+pub mod symbol;
 pub mod utils;
 
 use ast::Expr;
@@ -71,6 +75,18 @@ pub fn eval(input: &str) -> LangResult<Vec<Expr>> {
     Ok(ast)
 }
 
+// Evaluates every expression of `code` in a new environment,
+// and writes the value of the last one.
+#[cfg(test)]
+fn run(code: &str) -> LangResult<String> {
+    let mut env = env::Env::default();
+    let mut value = Expr::Nil;
+    for expr in eval(code)? {
+        value = eval::eval(&expr, &mut env)?;
+    }
+    Ok(ast::Written(&value).to_string())
+}
+
 pub fn debug(parsed_exprs: Pair<Rule>) {
     for expr in parsed_exprs.into_inner() {
         println!("{:?}:\n {}\n", expr.clone(), expr.as_str());
@@ -0,0 +1,469 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    ast::{Expr, Written},
+    env::Env,
+    eval::is_keyword,
+    symbol::Symbol,
+    LangError, LangResult,
+};
+
+const ELLIPSIS: &str = "...";
+const UNDERSCORE: &str = "_";
+
+// NOTE:
+// Hygiene is implemented by renaming.
+// Every symbol a template introduces (except special forms and quoted data)
+// is renamed to `name#n`, where `n` is unique for each expansion,
+// so bindings introduced by a macro never capture the user's variables.
+// A renamed symbol which isn't bound by the expansion itself
+// is looked up by its original name where the macro is defined,
+// so the user's variables never capture the macro's free symbols either, see `Env::expand`.
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub literals: Rc<[Symbol]>,
+    pub rules: Rc<[(Expr, Expr)]>,
+    // Where the macro is defined.
+    pub env: Env,
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Expr),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<Symbol, Binding>;
+
+impl Macro {
+    // (syntax-rules (literal ...) (pattern template) ...)
+    pub fn new(spec: &Expr, env: &Env) -> LangResult<Macro> {
+        let spec = spec.inner_s_expr()?;
+        match spec.first() {
+            Some(Expr::Symbol(s)) if s == "syntax-rules" => {}
+            _ => return Err(LangError::Other("expected syntax-rules".into())),
+        }
+
+        let literals = match spec.get(1) {
+            Some(Expr::SExpr(literals)) => literals
+                .iter()
+                .map(|literal| Ok(*literal.inner_symbol()?))
+                .collect::<LangResult<_>>()?,
+            _ => return Err(LangError::InvalidArgsLen),
+        };
+
+        let rules: Rc<[(Expr, Expr)]> = spec[2..]
+            .iter()
+            .map(|rule| match rule {
+                Expr::SExpr(rule) if rule.len() == 2 => Ok((rule[0].clone(), rule[1].clone())),
+                _ => Err(LangError::Other(
+                    "expected a rule like (pattern template)".into(),
+                )),
+            })
+            .collect::<LangResult<_>>()?;
+
+        let env = env.clone();
+        let syntax = Macro {
+            literals,
+            rules,
+            env,
+        };
+        for (pattern, _) in syntax.rules.iter() {
+            syntax.check_pattern(pattern)?;
+        }
+        Ok(syntax)
+    }
+
+    // A pattern variable may appear only once in a pattern,
+    // otherwise it would be bound to two inputs.
+    fn check_pattern(&self, pattern: &Expr) -> LangResult<()> {
+        let (items, tail) = split_list(pattern)?;
+        let mut vars = vec![];
+        for var in items
+            .iter()
+            .skip(1)
+            .chain(tail.as_ref())
+            .flat_map(|p| self.pattern_vars(p))
+        {
+            if vars.contains(&var) {
+                return Err(LangError::Other(format!(
+                    "duplicate pattern variable: {var}"
+                )));
+            }
+            vars.push(var);
+        }
+        Ok(())
+    }
+
+    // Expands `form` with the first rule whose pattern matches it,
+    // renaming the symbols of the template with `expansion`.
+    // The keyword position of patterns is ignored.
+    pub fn expand(&self, form: &[Expr], expansion: u32) -> LangResult<Expr> {
+        for (pattern, template) in self.rules.iter() {
+            let (items, tail) = split_list(pattern)?;
+            let Some(items) = items.get(1..) else {
+                continue;
+            };
+
+            let mut bindings = Bindings::new();
+            if self.match_list(items, tail.as_ref(), &form[1..], &mut bindings)? {
+                return expand_template(template, &bindings, expansion, false);
+            }
+        }
+
+        let form = Expr::SExpr(form.into());
+        Err(LangError::Other(format!(
+            "no syntax rule matches: {}",
+            Written(&form)
+        )))
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &Expr,
+        input: &Expr,
+        bindings: &mut Bindings,
+    ) -> LangResult<bool> {
+        match pattern {
+            Expr::Symbol(s) if s == UNDERSCORE => Ok(true),
+            Expr::Symbol(s) if self.literals.contains(s) => Ok(match input {
+                Expr::Symbol(input) => input.original().unwrap_or(*input) == *s,
+                _ => false,
+            }),
+            Expr::Symbol(s) => {
+                bindings.insert(*s, Binding::One(input.clone()));
+                Ok(true)
+            }
+            Expr::SExpr(_) | Expr::Pair(_) => {
+                let Expr::SExpr(input) = input else {
+                    return Ok(false);
+                };
+                let (items, tail) = split_list(pattern)?;
+                self.match_list(&items, tail.as_ref(), input, bindings)
+            }
+            pattern => Ok(pattern == input),
+        }
+    }
+
+    // Matches `(item ...)`, `(item ... . tail)` or `(before ... repeated ... after ...)`.
+    fn match_list(
+        &self,
+        items: &[Expr],
+        tail: Option<&Expr>,
+        input: &[Expr],
+        bindings: &mut Bindings,
+    ) -> LangResult<bool> {
+        let Some(idx) = items.iter().position(is_ellipsis) else {
+            let enough = match tail {
+                Some(_) => input.len() >= items.len(),
+                None => input.len() == items.len(),
+            };
+            if !enough {
+                return Ok(false);
+            }
+            for (pattern, input) in items.iter().zip(input) {
+                if !self.match_pattern(pattern, input, bindings)? {
+                    return Ok(false);
+                }
+            }
+            return match tail {
+                Some(tail) => {
+                    let rest = Expr::SExpr(input[items.len()..].into());
+                    self.match_pattern(tail, &rest, bindings)
+                }
+                None => Ok(true),
+            };
+        };
+
+        if idx == 0 || tail.is_some() {
+            return Err(LangError::Other("misplaced ellipsis in pattern".into()));
+        }
+        let (before, repeated, after) = (&items[..idx - 1], &items[idx - 1], &items[idx + 1..]);
+        if input.len() < before.len() + after.len() {
+            return Ok(false);
+        }
+        let middle = input.len() - after.len();
+
+        if !self.match_list(before, None, &input[..before.len()], bindings)?
+            || !self.match_list(after, None, &input[middle..], bindings)?
+        {
+            return Ok(false);
+        }
+
+        let mut matches = vec![];
+        for input in &input[before.len()..middle] {
+            let mut repeated_bindings = Bindings::new();
+            if !self.match_pattern(repeated, input, &mut repeated_bindings)? {
+                return Ok(false);
+            }
+            matches.push(repeated_bindings);
+        }
+        for var in self.pattern_vars(repeated) {
+            let many = matches
+                .iter_mut()
+                .map(|m| m.remove(&var).unwrap())
+                .collect();
+            bindings.insert(var, Binding::Many(many));
+        }
+        Ok(true)
+    }
+
+    fn pattern_vars(&self, pattern: &Expr) -> Vec<Symbol> {
+        match pattern {
+            Expr::Symbol(s) if s == UNDERSCORE || s == ELLIPSIS => vec![],
+            Expr::Symbol(s) if self.literals.contains(s) => vec![],
+            Expr::Symbol(s) => vec![*s],
+            Expr::SExpr(_) | Expr::Pair(_) => {
+                let Ok((items, tail)) = split_list(pattern) else {
+                    return vec![];
+                };
+                items
+                    .iter()
+                    .chain(tail.as_ref())
+                    .flat_map(|p| self.pattern_vars(p))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+fn is_ellipsis(expr: &Expr) -> bool {
+    matches!(expr, Expr::Symbol(s) if s == ELLIPSIS)
+}
+
+// Splits a list pattern or template into its items and the tail after the dot.
+fn split_list(expr: &Expr) -> LangResult<(Vec<Expr>, Option<Expr>)> {
+    match expr {
+        Expr::SExpr(items) => Ok((items.to_vec(), None)),
+        Expr::Pair(_) => {
+            let mut items = vec![];
+            let mut rest = expr.clone();
+            while let Expr::Pair(pair) = rest {
+                items.push(pair.car.borrow().to_syntax());
+                rest = pair.cdr.borrow().clone();
+            }
+            match rest {
+                Expr::Nil => Ok((items, None)),
+                tail => Ok((items, Some(tail.to_syntax()))),
+            }
+        }
+        _ => Err(LangError::Other(format!(
+            "expected a list pattern: {}",
+            Written(expr)
+        ))),
+    }
+}
+
+// Whether the items of a list template are quoted data.
+// Quasiquoted data is quoted too, except for the unquoted parts.
+fn is_quoted(items: &[Expr], quoted: bool) -> bool {
+    match items.first() {
+        Some(Expr::Symbol(s)) if s == "quote" || s == "quasiquote" => true,
+        Some(Expr::Symbol(s)) if s == "unquote" || s == "unquote-splicing" => false,
+        _ => quoted,
+    }
+}
+
+// Pattern variables are substituted everywhere,
+// but symbols inside quoted data are never renamed.
+fn expand_template(
+    template: &Expr,
+    bindings: &Bindings,
+    expansion: u32,
+    quoted: bool,
+) -> LangResult<Expr> {
+    match template {
+        Expr::Symbol(s) => match bindings.get(s) {
+            Some(Binding::One(expr)) => Ok(expr.clone()),
+            Some(Binding::Many(_)) => Err(LangError::Other(format!(
+                "pattern variable used without ellipsis: {s}"
+            ))),
+            None if quoted || is_keyword(s.as_str()) || s == ELLIPSIS || s == UNDERSCORE => {
+                Ok(template.clone())
+            }
+            None => Ok(Expr::Symbol(s.rename(expansion))),
+        },
+        Expr::SExpr(_) | Expr::Pair(_) => {
+            let (items, tail) = split_list(template)?;
+            let quoted = is_quoted(&items, quoted);
+            let items = expand_items(&items, bindings, expansion, quoted)?;
+            match tail.map(|tail| expand_template(&tail, bindings, expansion, quoted)) {
+                None => Ok(Expr::SExpr(items.into())),
+                Some(tail) => match tail? {
+                    Expr::SExpr(tail) => Ok(Expr::SExpr([&items[..], &tail].concat().into())),
+                    tail => Ok(Expr::list(items, tail)),
+                },
+            }
+        }
+        _ => Ok(template.clone()),
+    }
+}
+
+// Expands the items of a list template, splicing in `item ...` once per match.
+fn expand_items(
+    items: &[Expr],
+    bindings: &Bindings,
+    expansion: u32,
+    quoted: bool,
+) -> LangResult<Vec<Expr>> {
+    let mut result = vec![];
+    let mut iter = items.iter().peekable();
+
+    while let Some(item) = iter.next() {
+        if iter.next_if(|next| is_ellipsis(next)).is_none() {
+            result.push(expand_template(item, bindings, expansion, quoted)?);
+            continue;
+        }
+
+        let vars: Vec<_> = template_vars(item)
+            .into_iter()
+            .filter_map(|var| match bindings.get(&var) {
+                Some(Binding::Many(many)) => Some((var, many)),
+                _ => None,
+            })
+            .collect();
+        let Some(len) = vars.first().map(|(_, many)| many.len()) else {
+            return Err(LangError::Other(format!(
+                "no pattern variable to repeat: {}",
+                Written(item)
+            )));
+        };
+        if vars.iter().any(|(_, many)| many.len() != len) {
+            return Err(LangError::Other(format!(
+                "pattern variables repeat unevenly: {}",
+                Written(item)
+            )));
+        }
+
+        for idx in 0..len {
+            let mut bindings = bindings.clone();
+            for (var, many) in &vars {
+                bindings.insert(*var, many[idx].clone());
+            }
+            result.push(expand_template(item, &bindings, expansion, quoted)?);
+        }
+    }
+
+    Ok(result)
+}
+
+fn template_vars(template: &Expr) -> Vec<Symbol> {
+    match template {
+        Expr::Symbol(s) => vec![*s],
+        Expr::SExpr(_) | Expr::Pair(_) => {
+            let Ok((items, tail)) = split_list(template) else {
+                return vec![];
+            };
+            items
+                .iter()
+                .chain(tail.as_ref())
+                .flat_map(template_vars)
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run;
+
+    #[test]
+    fn ellipsis_matches_any_number_of_items() {
+        let my_list = "(define-syntax my-list (syntax-rules () ((_ x ...) (list x ...))))";
+        assert_eq!(
+            run(&format!("{my_list} (my-list 1 2 3)")).unwrap(),
+            "(1 2 3)"
+        );
+        assert_eq!(run(&format!("{my_list} (my-list)")).unwrap(), "nil");
+
+        let ends = "(define-syntax ends (syntax-rules () ((_ a b ... c) '(a c (b ...)))))";
+        assert_eq!(
+            run(&format!("{ends} (ends 1 2 3 4)")).unwrap(),
+            "(1 4 (2 3))"
+        );
+        assert_eq!(run(&format!("{ends} (ends 1 2)")).unwrap(), "(1 2 nil)");
+        assert!(run(&format!("{ends} (ends 1)")).is_err());
+    }
+
+    #[test]
+    fn nested_ellipsis() {
+        let tails = "(define-syntax tails (syntax-rules () ((_ (a b ...) ...) '((b ...) ...))))";
+        assert_eq!(
+            run(&format!("{tails} (tails (1 2 3) (4) (5 6))")).unwrap(),
+            "((2 3) nil (6))"
+        );
+    }
+
+    #[test]
+    fn dotted_pattern_matches_the_rest() {
+        let rest = "(define-syntax rest (syntax-rules () ((_ a . b) 'b)))";
+        assert_eq!(run(&format!("{rest} (rest 1 2 3)")).unwrap(), "(2 3)");
+        assert_eq!(run(&format!("{rest} (rest 1)")).unwrap(), "nil");
+        assert!(run(&format!("{rest} (rest)")).is_err());
+    }
+
+    #[test]
+    fn empty_lists_stay_code() {
+        let empty = "(define-syntax empty (syntax-rules () ((_ () . rest) 'rest)))";
+        assert_eq!(run(&format!("{empty} (empty () 1 2)")).unwrap(), "(1 2)");
+        assert!(run(&format!("{empty} (empty (1) 1 2)")).is_err());
+
+        // The argument of `macroexpand` is data, its empty list must still match `()`.
+        assert_eq!(
+            run(&format!("{empty} (macroexpand '(empty () 1 2))")).unwrap(),
+            "(quote (1 2))"
+        );
+        // Expansions print the empty list as `nil`, which reads back as code too.
+        assert_eq!(run("(let nil 1)").unwrap(), "1");
+        assert_eq!(run("((lambda nil 1))").unwrap(), "1");
+        let outer = "(define-syntax outer (syntax-rules () ((_ x) (empty x 1))))";
+        assert_eq!(
+            run(&format!("{empty} {outer} (macroexpand '(outer ()))")).unwrap(),
+            "(quote (1))"
+        );
+    }
+
+    #[test]
+    fn literals_match_only_themselves() {
+        let my_if = "(define-syntax my-if (syntax-rules (then else) \
+            ((_ c then t else e) (if c t e))))";
+        assert_eq!(
+            run(&format!("{my_if} (my-if #f then 1 else 2)")).unwrap(),
+            "2"
+        );
+        assert!(run(&format!("{my_if} (my-if #f 1 2 3 4)")).is_err());
+    }
+
+    #[test]
+    fn duplicate_pattern_variables_are_rejected() {
+        assert!(run("(define-syntax m (syntax-rules () ((_ (a a) ...) 1)))").is_err());
+        assert!(run("(define-syntax m (syntax-rules () ((_ a . a) 1)))").is_err());
+    }
+
+    #[test]
+    fn templates_are_hygienic() {
+        let swap = "(define-syntax swap! (syntax-rules () \
+            ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+        assert_eq!(
+            run(&format!(
+                "{swap} (define tmp 1) (define y 2) (swap! tmp y) (list tmp y)"
+            ))
+            .unwrap(),
+            "(2 1)"
+        );
+
+        let my_inc = "(define-syntax my-inc (syntax-rules () ((_ x) (+ x 1))))";
+        assert_eq!(
+            run(&format!("{my_inc} (let ((+ -)) (my-inc 10))")).unwrap(),
+            "11"
+        );
+
+        let getx = "(define x 10) (define-syntax getx (syntax-rules () ((_) x)))";
+        assert_eq!(
+            run(&format!("{getx} (define (f x) (getx)) (f 1)")).unwrap(),
+            "10"
+        );
+    }
+}
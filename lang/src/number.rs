@@ -0,0 +1,594 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    mem,
+    ops::{Add, Mul, Neg, Sub},
+    rc::Rc,
+    str::FromStr,
+};
+
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{Num, One, Signed, ToPrimitive, Zero};
+
+use crate::{LangError, LangResult};
+
+// The numeric tower: exact integers and rationals, and inexact reals.
+// Exact arithmetic never overflows or rounds, it moves up to `Big`
+// and `Ratio` when needed, and results are always normalized back
+// down to the simplest representation, so every exact number has
+// exactly one representation:
+// - `Int` holds every integer that fits in `i64`
+// - `Big` holds the integers that don't
+// - `Ratio` holds the non-integers
+// Mixing an exact number with an inexact one gives an inexact result.
+//
+// Integers are computed with `BigInt` directly, only an operation
+// with a `Ratio` goes through `BigRational`, which reduces every result.
+#[derive(Debug, Clone)]
+pub enum Number {
+    Int(i64),
+    Big(Rc<BigInt>),
+    Ratio(Rc<BigRational>),
+    Float(f64),
+}
+
+macro_rules! arithmetic {
+    ($trait:ident, $method:ident, $checked:ident) => {
+        impl $trait for Number {
+            type Output = Number;
+
+            fn $method(self, rhs: Number) -> Number {
+                if let (Number::Int(x), Number::Int(y)) = (&self, &rhs) {
+                    if let Some(n) = x.$checked(*y) {
+                        return Number::Int(n);
+                    }
+                }
+                if let (Some(x), Some(y)) = (self.big(), rhs.big()) {
+                    return Number::from_big(x.as_ref().$method(y.as_ref()));
+                }
+                match (self.ratio(), rhs.ratio()) {
+                    (Some(x), Some(y)) => Number::from_ratio(x.$method(y)),
+                    _ => Number::Float(self.to_f64().$method(rhs.to_f64())),
+                }
+            }
+        }
+    };
+}
+
+arithmetic!(Add, add, checked_add);
+arithmetic!(Sub, sub, checked_sub);
+arithmetic!(Mul, mul, checked_mul);
+
+impl Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Number {
+        match self {
+            Number::Int(n) => n
+                .checked_neg()
+                .map_or_else(|| Number::from_big(-BigInt::from(n)), Number::Int),
+            Number::Big(n) => Number::from_big(-n.as_ref()),
+            Number::Ratio(r) => Number::Ratio(Rc::new(-r.as_ref())),
+            Number::Float(n) => Number::Float(-n),
+        }
+    }
+}
+
+impl Number {
+    pub fn from_big(n: BigInt) -> Number {
+        match n.to_i64() {
+            Some(n) => Number::Int(n),
+            None => Number::Big(Rc::new(n)),
+        }
+    }
+
+    pub fn from_ratio(r: BigRational) -> Number {
+        match r.is_integer() {
+            true => Number::from_big(r.to_integer()),
+            false => Number::Ratio(Rc::new(r)),
+        }
+    }
+
+    // The value of an exact integer, `None` for any other number.
+    fn big(&self) -> Option<Cow<'_, BigInt>> {
+        match self {
+            Number::Int(n) => Some(Cow::Owned((*n).into())),
+            Number::Big(n) => Some(Cow::Borrowed(n)),
+            _ => None,
+        }
+    }
+
+    // The value of an exact number, `None` for an inexact one.
+    fn ratio(&self) -> Option<BigRational> {
+        match self {
+            Number::Int(n) => Some(BigRational::from_integer((*n).into())),
+            Number::Big(n) => Some(BigRational::from_integer(n.as_ref().clone())),
+            Number::Ratio(r) => Some(r.as_ref().clone()),
+            Number::Float(_) => None,
+        }
+    }
+
+    // Like `ratio`, but inexact numbers are converted exactly too.
+    fn exact_ratio(&self) -> LangResult<BigRational> {
+        match self {
+            Number::Float(n) => BigRational::from_float(*n).ok_or_else(|| {
+                LangError::Other(format!("can't convert {self} to an exact number"))
+            }),
+            _ => Ok(self.ratio().unwrap()),
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Big(n) => n.to_f64().unwrap_or(f64::NAN),
+            Number::Ratio(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(n) => *n,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Float(_))
+    }
+
+    // The equality of `eqv?`, stricter than `==`:
+    // exact and inexact numbers are never the same, so `(eqv? 1 1.0)` is false,
+    // and floats are the same when their bits are, so `+nan.0` is itself.
+    // Exact numbers have only one representation, so comparing them is structural.
+    pub fn eqv(&self, other: &Number) -> bool {
+        match (self, other) {
+            (Number::Int(x), Number::Int(y)) => x == y,
+            (Number::Big(x), Number::Big(y)) => x == y,
+            (Number::Ratio(x), Number::Ratio(y)) => x == y,
+            (Number::Float(x), Number::Float(y)) => x.to_bits() == y.to_bits(),
+            _ => false,
+        }
+    }
+
+    // A hash consistent with `eqv`, not with `==`.
+    pub fn hash_eqv<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Number::Int(n) => n.hash(state),
+            Number::Big(n) => n.hash(state),
+            Number::Ratio(r) => r.hash(state),
+            Number::Float(n) => n.to_bits().hash(state),
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Number::Int(_) | Number::Big(_) => true,
+            Number::Ratio(_) => false,
+            Number::Float(n) => n.is_finite() && n.fract() == 0.0,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(n) => *n == 0,
+            Number::Big(_) | Number::Ratio(_) => false,
+            Number::Float(n) => *n == 0.0,
+        }
+    }
+
+    pub fn to_inexact(&self) -> Number {
+        Number::Float(self.to_f64())
+    }
+
+    // Inexact numbers are converted to the exact value they hold,
+    // so `(inexact->exact 0.5)` is `1/2`.
+    pub fn to_exact(&self) -> LangResult<Number> {
+        self.exact_ratio().map(Number::from_ratio)
+    }
+
+    pub fn try_div(&self, rhs: &Number) -> LangResult<Number> {
+        if rhs.is_zero() {
+            return Err(LangError::DivideByZero);
+        }
+        if let (Number::Int(x), Number::Int(y)) = (self, rhs) {
+            if x.checked_rem(*y) == Some(0) {
+                return Ok(Number::Int(x / y));
+            }
+        }
+        match (self.ratio(), rhs.ratio()) {
+            (Some(x), Some(y)) => Ok(Number::from_ratio(x / y)),
+            _ => Ok(Number::Float(self.to_f64() / rhs.to_f64())),
+        }
+    }
+
+    // Rounds towards zero.
+    pub fn quotient(&self, rhs: &Number) -> LangResult<Number> {
+        self.int_div(rhs, i64::checked_div, |x, y| x / y)
+    }
+
+    // Has the same sign as the dividend.
+    pub fn remainder(&self, rhs: &Number) -> LangResult<Number> {
+        self.int_div(rhs, i64::checked_rem, |x, y| x % y)
+    }
+
+    // Has the same sign as the divisor.
+    pub fn modulo(&self, rhs: &Number) -> LangResult<Number> {
+        self.int_div(
+            rhs,
+            |x, y| x.checked_rem(y).map(|_| x.mod_floor(&y)),
+            |x, y| x.mod_floor(y),
+        )
+    }
+
+    // Always non-negative, `(gcd 0 0)` is `0`.
+    pub fn gcd(&self, rhs: &Number) -> LangResult<Number> {
+        self.int_op(
+            rhs,
+            |x, y| Some(x.checked_abs()?.gcd(&y.checked_abs()?)),
+            |x, y| x.gcd(y),
+        )
+    }
+
+    // Always non-negative, `(lcm x 0)` is `0`.
+    pub fn lcm(&self, rhs: &Number) -> LangResult<Number> {
+        self.int_op(
+            rhs,
+            |x, y| {
+                let (x, y) = (x.checked_abs()?, y.checked_abs()?);
+                match x == 0 || y == 0 {
+                    true => Some(0),
+                    false => (x / x.gcd(&y)).checked_mul(y),
+                }
+            },
+            |x, y| x.lcm(y),
+        )
+    }
+
+    pub fn numerator(&self) -> LangResult<Number> {
+        match self.ratio() {
+            Some(r) => Ok(Number::from_big(r.numer().clone())),
+            None => Ok(self.to_exact()?.numerator()?.to_inexact()),
+        }
+    }
+
+    pub fn denominator(&self) -> LangResult<Number> {
+        match self.ratio() {
+            Some(r) => Ok(Number::from_big(r.denom().clone())),
+            None => Ok(self.to_exact()?.denominator()?.to_inexact()),
+        }
+    }
+
+    // The simplest rational number differing from `self` by no more than `y`.
+    pub fn rationalize(&self, y: &Number) -> LangResult<Number> {
+        let (x_ratio, y_ratio) = (self.exact_ratio()?, y.exact_ratio()?.abs());
+        let lo = &x_ratio - &y_ratio;
+        let hi = &x_ratio + &y_ratio;
+        let simplest = Number::from_ratio(simplest_between(&lo, &hi));
+        Ok(simplest.with_exactness(self.is_exact() && y.is_exact()))
+    }
+
+    pub fn is_positive(&self) -> bool {
+        match self {
+            Number::Int(n) => *n > 0,
+            Number::Big(n) => n.is_positive(),
+            Number::Ratio(r) => r.is_positive(),
+            Number::Float(n) => *n > 0.0,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Number::Int(n) => *n < 0,
+            Number::Big(n) => n.is_negative(),
+            Number::Ratio(r) => r.is_negative(),
+            Number::Float(n) => *n < 0.0,
+        }
+    }
+
+    pub fn abs(&self) -> Number {
+        match self.is_negative() {
+            true => -self.clone(),
+            false => self.clone(),
+        }
+    }
+
+    pub fn floor(&self) -> Number {
+        self.round_with(BigRational::floor, f64::floor)
+    }
+
+    pub fn ceiling(&self) -> Number {
+        self.round_with(BigRational::ceil, f64::ceil)
+    }
+
+    pub fn truncate(&self) -> Number {
+        self.round_with(BigRational::trunc, f64::trunc)
+    }
+
+    // Rounds to even on ties, so `(round 2.5)` is `2.0`.
+    pub fn round(&self) -> Number {
+        self.round_with(round_ties_even, f64::round_ties_even)
+    }
+
+    // Exact for exact perfect squares like `(sqrt 16)` or `(sqrt 1/4)`.
+    pub fn sqrt(&self) -> LangResult<Number> {
+        if self.is_negative() {
+            return Err(LangError::Other(format!(
+                "can't take the square root of a negative number: {self}"
+            )));
+        }
+        if let Some(r) = self.ratio() {
+            let (numer, denom) = (r.numer().sqrt(), r.denom().sqrt());
+            if &numer * &numer == *r.numer() && &denom * &denom == *r.denom() {
+                return Ok(Number::from_ratio(BigRational::new(numer, denom)));
+            }
+        }
+        Ok(Number::Float(self.to_f64().sqrt()))
+    }
+
+    // Exact when an exact number is raised to an exact integer power.
+    pub fn expt(&self, power: &Number) -> LangResult<Number> {
+        if let (Some(base), Number::Int(power)) = (self.ratio(), power) {
+            if base.is_zero() && *power < 0 {
+                return Err(LangError::DivideByZero);
+            }
+            let power = i32::try_from(*power)
+                .map_err(|_| LangError::Other(format!("exponent is too large: {power}")))?;
+            return Ok(Number::from_ratio(base.pow(power)));
+        }
+        Ok(Number::Float(self.to_f64().powf(power.to_f64())))
+    }
+
+    pub fn to_string_radix(&self, radix: u32) -> LangResult<String> {
+        match self {
+            _ if radix == 10 => Ok(self.to_string()),
+            Number::Int(n) => Ok(BigInt::from(*n).to_str_radix(radix)),
+            Number::Big(n) => Ok(n.to_str_radix(radix)),
+            Number::Ratio(r) => Ok(format!(
+                "{}/{}",
+                r.numer().to_str_radix(radix),
+                r.denom().to_str_radix(radix)
+            )),
+            Number::Float(_) => Err(LangError::Other(
+                "inexact numbers can only be written in radix 10".into(),
+            )),
+        }
+    }
+
+    // Literals with a decimal point or an exponent are inexact,
+    // everything else is exact, including rationals like `1/3`.
+    // Only radix 10 supports inexact literals, including `+inf.0` and `+nan.0`.
+    pub fn from_str_radix(s: &str, radix: u32) -> LangResult<Number> {
+        let parse_failed = || LangError::ParseFailed(format!("not a number: {s}"));
+        let parse_big = |s: &str| BigInt::from_str_radix(s, radix).map_err(|_| parse_failed());
+
+        // `BigInt` accepts `_` as a separator, but the reader doesn't.
+        if s.is_empty() || s.contains('_') {
+            return Err(parse_failed());
+        }
+        match s {
+            "+inf.0" if radix == 10 => return Ok(Number::Float(f64::INFINITY)),
+            "-inf.0" if radix == 10 => return Ok(Number::Float(f64::NEG_INFINITY)),
+            "+nan.0" | "-nan.0" if radix == 10 => return Ok(Number::Float(f64::NAN)),
+            _ => {}
+        }
+        if let Some((numer, denom)) = s.split_once('/') {
+            let (numer, denom) = (parse_big(numer)?, parse_big(denom)?);
+            if denom.is_zero() {
+                return Err(LangError::DivideByZero);
+            }
+            return Ok(Number::from_ratio(BigRational::new(numer, denom)));
+        }
+        if radix == 10 && s.contains(['.', 'e', 'E']) {
+            return s.parse().map(Number::Float).map_err(|_| parse_failed());
+        }
+        match i64::from_str_radix(s, radix) {
+            Ok(n) => Ok(Number::Int(n)),
+            Err(_) => parse_big(s).map(Number::from_big),
+        }
+    }
+
+    fn with_exactness(self, exact: bool) -> Number {
+        match exact {
+            true => self,
+            false => self.to_inexact(),
+        }
+    }
+
+    fn round_with(
+        &self,
+        ratio_op: fn(&BigRational) -> BigRational,
+        float_op: fn(f64) -> f64,
+    ) -> Number {
+        match self {
+            Number::Ratio(r) => Number::from_ratio(ratio_op(r)),
+            Number::Float(n) => Number::Float(float_op(*n)),
+            n => n.clone(),
+        }
+    }
+
+    // Integer division, see `int_op`.
+    fn int_div<I, B>(&self, rhs: &Number, int_op: I, big_op: B) -> LangResult<Number>
+    where
+        I: Fn(i64, i64) -> Option<i64>,
+        B: Fn(&BigInt, &BigInt) -> BigInt,
+    {
+        if rhs.is_zero() {
+            return Err(LangError::DivideByZero);
+        }
+        self.int_op(rhs, int_op, big_op)
+    }
+
+    // An operation on two exact integers, or on inexact numbers without
+    // a fractional part like `(quotient 7.0 2)`, which give an inexact result.
+    // `int_op` is the fast path, returning `None` when it would overflow.
+    fn int_op<I, B>(&self, rhs: &Number, int_op: I, big_op: B) -> LangResult<Number>
+    where
+        I: Fn(i64, i64) -> Option<i64>,
+        B: Fn(&BigInt, &BigInt) -> BigInt,
+    {
+        if !self.is_integer() || !rhs.is_integer() {
+            return Err(LangError::Other(format!(
+                "expected integers: {self} and {rhs}"
+            )));
+        }
+        if let (Number::Int(x), Number::Int(y)) = (self, rhs) {
+            if let Some(n) = int_op(*x, *y) {
+                return Ok(Number::Int(n));
+            }
+        }
+        if let (Some(x), Some(y)) = (self.big(), rhs.big()) {
+            return Ok(Number::from_big(big_op(&x, &y)));
+        }
+        let (x, y) = (self.exact_ratio()?, rhs.exact_ratio()?);
+        let n = Number::from_big(big_op(x.numer(), y.numer()));
+        Ok(n.with_exactness(self.is_exact() && rhs.is_exact()))
+    }
+}
+
+fn round_ties_even(r: &BigRational) -> BigRational {
+    let floor = r.floor();
+    let ceil = &floor + BigRational::one();
+    let half = BigRational::new(1.into(), 2.into());
+    match (r - &floor).cmp(&half) {
+        Ordering::Less => floor,
+        Ordering::Greater => ceil,
+        Ordering::Equal if floor.to_integer().is_even() => floor,
+        Ordering::Equal => ceil,
+    }
+}
+
+// The rational with the smallest denominator in `[lo, hi]`,
+// found by walking down the continued fraction expansions.
+fn simplest_between(lo: &BigRational, hi: &BigRational) -> BigRational {
+    if !lo.is_positive() && !hi.is_negative() {
+        return BigRational::zero();
+    }
+    if hi.is_negative() {
+        return -simplest_between(&-hi, &-lo);
+    }
+    let floor = lo.floor();
+    if &floor == lo {
+        return floor;
+    }
+    let ceil = &floor + BigRational::one();
+    if &ceil <= hi {
+        return ceil;
+    }
+    let rest = simplest_between(&(hi - &floor).recip(), &(lo - &floor).recip());
+    floor + rest.recip()
+}
+
+// Numbers compare by value, so `(= 1 1.0)` holds.
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(x), Number::Int(y)) => x.partial_cmp(y),
+            (Number::Float(x), Number::Float(y)) => x.partial_cmp(y),
+            (Number::Float(x), y) => compare_float(*x, y),
+            (x, Number::Float(y)) => compare_float(*y, x).map(Ordering::reverse),
+            (x, y) => x.ratio().partial_cmp(&y.ratio()),
+        }
+    }
+}
+
+// Compares a float with an exact number exactly, not by converting the exact
+// number to a float, which rounds, so `=` stays transitive.
+fn compare_float(x: f64, exact: &Number) -> Option<Ordering> {
+    match BigRational::from_float(x) {
+        Some(x) => x.partial_cmp(&exact.ratio()?),
+        None if x.is_nan() => None,
+        None if x > 0.0 => Some(Ordering::Greater),
+        None => Some(Ordering::Less),
+    }
+}
+
+impl FromStr for Number {
+    type Err = LangError;
+
+    fn from_str(s: &str) -> LangResult<Number> {
+        Number::from_str_radix(s, 10)
+    }
+}
+
+// Inexact numbers always print with a decimal point or an exponent,
+// so they read back as inexact numbers.
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{n}"),
+            Number::Big(n) => write!(f, "{n}"),
+            Number::Ratio(r) => write!(f, "{r}"),
+            Number::Float(n) if n.is_nan() => write!(f, "+nan.0"),
+            Number::Float(n) if n.is_infinite() => {
+                write!(f, "{}inf.0", if *n > 0.0 { "+" } else { "-" })
+            }
+            Number::Float(n) => write!(f, "{n:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(s: &str) -> Number {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn integers_move_between_int_and_big() {
+        let max = Number::Int(i64::MAX);
+        let big = max.clone() + Number::Int(1);
+        assert!(matches!(big, Number::Big(_)));
+        assert!(matches!(
+            big.clone() - Number::Int(1),
+            Number::Int(i64::MAX)
+        ));
+        assert!(matches!(-Number::Int(i64::MIN), Number::Big(_)));
+        assert!(matches!(num("99999999999999999999"), Number::Big(_)));
+        assert!(matches!(num("-9223372036854775808"), Number::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn ratios_are_normalized() {
+        assert!(matches!(num("4/2"), Number::Int(2)));
+        assert!(matches!(num("2/4"), Number::Ratio(_)));
+        assert_eq!(num("2/4").to_string(), "1/2");
+        assert!(matches!(num("1/2") + num("1/2"), Number::Int(1)));
+        assert!(matches!(num("1/3") * Number::Int(3), Number::Int(1)));
+        let big = num("99999999999999999999/3");
+        assert!(matches!(big, Number::Big(_)));
+        assert!(matches!(
+            big * num("1/33333333333333333333"),
+            Number::Int(1)
+        ));
+        assert!(matches!(num("1/2") + num("0.5"), Number::Float(_)));
+    }
+
+    #[test]
+    fn exact_and_inexact_compare_by_value() {
+        assert_eq!(num("1"), num("1.0"));
+        assert_eq!(num("1/2"), num("0.5"));
+        assert_ne!(num("1/3"), Number::Float(1.0 / 3.0));
+        assert_eq!(num("9007199254740992"), num("9007199254740992.0"));
+        // Rounds to the same float as the exact number above.
+        assert_ne!(num("9007199254740993"), num("9007199254740992.0"));
+        assert!(num("9007199254740993") > num("9007199254740992.0"));
+        assert!(num("+inf.0") > num("99999999999999999999"));
+        assert!(num("-inf.0") < num("-1/2"));
+        assert_ne!(num("+nan.0"), num("+nan.0"));
+        assert_eq!(num("1").partial_cmp(&num("+nan.0")), None);
+    }
+
+    #[test]
+    fn eqv_distinguishes_exactness() {
+        assert!(num("2").eqv(&num("4/2")));
+        assert!(!num("1").eqv(&num("1.0")));
+    }
+}
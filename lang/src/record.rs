@@ -0,0 +1,174 @@
+use std::{cell::RefCell, fmt::Display, rc::Rc};
+
+use crate::{ast::Expr, env::Env, symbol::Symbol, utils::expected, LangError, LangResult};
+
+// A type defined by `define-record-type`.
+// Every definition creates a new type, even with a name which is already used,
+// so records of the old type aren't accepted by the new procedures.
+#[derive(Debug)]
+pub struct RecordType {
+    // Without the angle brackets of `<point>`.
+    pub name: Symbol,
+    pub fields: Rc<[Symbol]>,
+}
+
+// A field which isn't set by the constructor is `nil`.
+#[derive(Debug)]
+pub struct Record {
+    pub rtd: Rc<RecordType>,
+    pub values: RefCell<Vec<Expr>>,
+}
+
+// The procedures defined by `define-record-type`.
+// They need their record type, which a plain `Expr::Fn` can't capture.
+#[derive(Debug)]
+pub enum RecordProc {
+    // The indexes of the fields set by the arguments, in order.
+    Constructor(Rc<RecordType>, Vec<usize>),
+    Predicate(Rc<RecordType>),
+    Accessor(Rc<RecordType>, usize),
+    Modifier(Rc<RecordType>, usize),
+}
+
+// (define-record-type <name> (constructor field ...) predicate (field accessor [modifier]) ...)
+// The constructor may be a single symbol, then it takes every field in order.
+pub fn define_record_type(args: &[Expr], env: &mut Env) -> LangResult<Expr> {
+    if args.len() < 3 {
+        return Err(LangError::InvalidArgsLen);
+    }
+
+    let name = args[0].inner_symbol()?.as_str();
+    let name = name
+        .strip_prefix('<')
+        .and_then(|s| s.strip_suffix('>'))
+        .unwrap_or(name);
+    let specs = args[3..]
+        .iter()
+        .map(|spec| match spec {
+            Expr::SExpr(spec) if matches!(spec.len(), 2 | 3) => spec
+                .iter()
+                .map(|s| Ok(*s.inner_symbol()?))
+                .collect::<LangResult<Vec<_>>>(),
+            _ => Err(LangError::Other(format!(
+                "expected a field like (field accessor [modifier]) in the record type {name}"
+            ))),
+        })
+        .collect::<LangResult<Vec<_>>>()?;
+    let rtd = Rc::new(RecordType {
+        name: name.into(),
+        fields: specs.iter().map(|spec| spec[0]).collect(),
+    });
+
+    let (constructor, fields) = match &args[1] {
+        Expr::Symbol(constructor) => (*constructor, (0..rtd.fields.len()).collect()),
+        Expr::SExpr(constructor) if !constructor.is_empty() => {
+            let fields = constructor[1..]
+                .iter()
+                .map(|field| {
+                    let field = field.inner_symbol()?;
+                    rtd.fields.iter().position(|f| f == field).ok_or_else(|| {
+                        LangError::Other(format!("unknown field {field} of the record type {name}"))
+                    })
+                })
+                .collect::<LangResult<_>>()?;
+            (*constructor[0].inner_symbol()?, fields)
+        }
+        _ => {
+            return Err(LangError::Other(
+                "expected a constructor like (name field ...)".into(),
+            ))
+        }
+    };
+    let predicate = *args[2].inner_symbol()?;
+
+    let mut define = |name: Symbol, proc: RecordProc| {
+        env.define(name, Expr::RecordProc(Rc::new(proc)));
+    };
+    define(constructor, RecordProc::Constructor(rtd.clone(), fields));
+    define(predicate, RecordProc::Predicate(rtd.clone()));
+    for (idx, spec) in specs.iter().enumerate() {
+        define(spec[1], RecordProc::Accessor(rtd.clone(), idx));
+        if let Some(modifier) = spec.get(2) {
+            define(*modifier, RecordProc::Modifier(rtd.clone(), idx));
+        }
+    }
+    Ok(args[0].clone())
+}
+
+impl RecordProc {
+    pub fn call(&self, args: &[Expr]) -> LangResult<Expr> {
+        match (self, args) {
+            (RecordProc::Constructor(rtd, fields), args) if args.len() == fields.len() => {
+                let mut values = vec![Expr::Nil; rtd.fields.len()];
+                for (idx, arg) in fields.iter().zip(args) {
+                    values[*idx] = arg.clone();
+                }
+                let values = RefCell::new(values);
+                Ok(Expr::Record(Rc::new(Record {
+                    rtd: rtd.clone(),
+                    values,
+                })))
+            }
+            (RecordProc::Predicate(rtd), [expr]) => Ok(Expr::Bool(
+                matches!(expr, Expr::Record(record) if Rc::ptr_eq(&record.rtd, rtd)),
+            )),
+            (RecordProc::Accessor(rtd, idx), [expr]) => {
+                Ok(instance(rtd, expr)?.values.borrow()[*idx].clone())
+            }
+            (RecordProc::Modifier(rtd, idx), [expr, value]) => {
+                instance(rtd, expr)?.values.borrow_mut()[*idx] = value.clone();
+                Ok(Expr::Nil)
+            }
+            _ => Err(LangError::InvalidArgsLen),
+        }
+    }
+}
+
+// Accessors and modifiers only accept records of their own type.
+fn instance<'a>(rtd: &Rc<RecordType>, expr: &'a Expr) -> LangResult<&'a Record> {
+    match expr {
+        Expr::Record(record) if Rc::ptr_eq(&record.rtd, rtd) => Ok(record),
+        _ => Err(expected(&format!("a {} record", rtd.name), 0, expr)),
+    }
+}
+
+impl Display for RecordProc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordProc::Constructor(rtd, _) => write!(f, "#<record-constructor {}>", rtd.name),
+            RecordProc::Predicate(rtd) => write!(f, "#<record-predicate {}>", rtd.name),
+            RecordProc::Accessor(rtd, idx) => {
+                write!(f, "#<record-accessor {} {}>", rtd.name, rtd.fields[*idx])
+            }
+            RecordProc::Modifier(rtd, idx) => {
+                write!(f, "#<record-modifier {} {}>", rtd.name, rtd.fields[*idx])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::run;
+
+    #[test]
+    fn records() {
+        let point = "(define-record-type point
+              (make-point x y)
+              point?
+              (x point-x set-point-x!)
+              (y point-y))
+            (define p (make-point 1 2))";
+        let run = |code: &str| run(&format!("{point} {code}"));
+        assert_eq!(run("p").unwrap(), "#<record point x=1 y=2>");
+        assert_eq!(run("(list (point? p) (point? 1))").unwrap(), "(#t #f)");
+        assert_eq!(run("(list (point-x p) (point-y p))").unwrap(), "(1 2)");
+        assert_eq!(run("(set-point-x! p 5) (point-x p)").unwrap(), "5");
+        assert!(run("(make-point 1)").is_err());
+        assert!(run("(point-x 1)").is_err());
+        // Records of another type with the same fields are rejected too.
+        let other = "(define-record-type other (make-other x) other? (x other-x))";
+        let err = run(&format!("{other} (point-x (make-other 1))")).unwrap_err();
+        assert!(err.to_string().contains("expected a point record"));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+// An interned symbol, a small id standing for its name,
+// so comparing, hashing and copying symbols never touches the names.
+// Symbols with the same name are the same symbol, except for renamed ones.
+//
+// NOTE:
+// Names are leaked and never freed, an interner can't know when a name
+// isn't used anymore. Every distinct name costs memory once.
+// Renaming a symbol doesn't intern a new name, a renamed symbol is
+// the same name with the id of the macro expansion which renamed it,
+// so expanding macros again and again doesn't grow the interner.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol {
+    name: u32,
+    // `0` for a symbol which isn't renamed.
+    expansion: u32,
+}
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, u32>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+// Renamed symbols print as `name#n`.
+// The reader never accepts `#` inside a symbol,
+// so a renamed symbol can't clash with any symbol written by the user.
+const ALIAS: char = '#';
+
+// Wraps around after 2^32 expansions, by then the old ones are long gone.
+static EXPANSIONS: AtomicU32 = AtomicU32::new(1);
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        let name = INTERNER.with_borrow_mut(|interner| match interner.ids.get(name) {
+            Some(id) => *id,
+            None => {
+                let id = interner.names.len() as u32;
+                let name: &'static str = Box::leak(name.into());
+                interner.names.push(name);
+                interner.ids.insert(name, id);
+                id
+            }
+        });
+        Symbol { name, expansion: 0 }
+    }
+
+    // A unique id for every macro expansion, never `0`.
+    pub fn next_expansion() -> u32 {
+        match EXPANSIONS.fetch_add(1, Ordering::Relaxed) {
+            0 => EXPANSIONS.fetch_add(1, Ordering::Relaxed),
+            expansion => expansion,
+        }
+    }
+
+    // A symbol different from every other symbol, even one with the same name,
+    // it's renamed like the symbols of a macro expansion which never happened.
+    pub fn gensym(prefix: &str) -> Symbol {
+        Symbol::new(prefix).rename(Symbol::next_expansion())
+    }
+
+    // The symbol renamed by the macro expansion `expansion`.
+    pub fn rename(self, expansion: u32) -> Symbol {
+        Symbol { expansion, ..self }
+    }
+
+    // The symbol before it was renamed, `None` if it isn't renamed.
+    pub fn original(self) -> Option<Symbol> {
+        match self.expansion {
+            0 => None,
+            _ => Some(Symbol {
+                expansion: 0,
+                ..self
+            }),
+        }
+    }
+
+    pub fn expansion(self) -> u32 {
+        self.expansion
+    }
+
+    // The name without the suffix of a renamed symbol.
+    pub fn as_str(&self) -> &'static str {
+        INTERNER.with_borrow(|interner| interner.names[self.name as usize])
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Symbol::new(&name)
+    }
+}
+
+// A renamed symbol never equals a name.
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.expansion == 0 && self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.expansion {
+            0 => write!(f, "{}", self.as_str()),
+            expansion => write!(f, "{}{ALIAS}{expansion}", self.as_str()),
+        }
+    }
+}
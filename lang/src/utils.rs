@@ -1,10 +1,12 @@
 use crate::{
-    ast::Expr,
-    env::{Env, Map},
-    eval::eval_args,
+    ast::{Expr, Lambda, Written},
+    env::Env,
+    eval::eval,
+    number::Number,
+    symbol::Symbol,
     LangError, LangResult,
 };
-use std::{cell::RefCell, convert::identity, rc::Rc};
+use std::rc::Rc;
 
 pub fn trim_bracket_outer(s: &str) -> &str {
     fn trim_recursive(s: &str, right_pos: usize) -> &str {
@@ -17,63 +19,153 @@ pub fn trim_bracket_outer(s: &str) -> &str {
     trim_recursive(s, s.len() - 1)
 }
 
-pub fn ensure<F>(args: &[Expr], body: F) -> LangResult<Expr>
-where
-    F: Fn(&&Expr, &&Expr) -> bool,
-{
-    let result = args.iter().map_windows(|[x, y]| body(x, y)).all(identity);
-    Ok(Expr::Bool(result))
+// The error for the argument at `idx` having the wrong type.
+pub fn expected(what: &str, idx: usize, expr: &Expr) -> LangError {
+    LangError::Other(format!(
+        "expected {what} as argument {}, got {}",
+        idx + 1,
+        Written(expr)
+    ))
 }
 
 pub fn parse_list_of_strings(args: &[Expr]) -> LangResult<Vec<Rc<str>>> {
+    (0..args.len()).map(|idx| parse_string(args, idx)).collect()
+}
+
+pub fn parse_list_of_chars(args: &[Expr]) -> LangResult<Vec<char>> {
     args.iter()
-        .map(|expr| match expr {
-            Expr::String(s) => Ok(s.clone()),
-            _ => Err(LangError::Other("expected a string".into())),
+        .enumerate()
+        .map(|(idx, expr)| match expr {
+            Expr::Char(c) => Ok(*c),
+            _ => Err(expected("a character", idx, expr)),
         })
         .collect()
 }
 
-pub fn parse_list_of_floats(args: &[Expr]) -> LangResult<Vec<f64>> {
+pub fn parse_list_of_numbers(args: &[Expr]) -> LangResult<Vec<Number>> {
     args.iter()
-        .map(|expr| match expr {
-            Expr::Num(num) => Ok(*num),
-            _ => Err(LangError::Other("expected a number".into())),
+        .enumerate()
+        .map(|(idx, expr)| match expr {
+            Expr::Num(num) => Ok(num.clone()),
+            _ => Err(expected("a number", idx, expr)),
         })
         .collect()
 }
 
+// The argument at `idx` should be a string.
+pub fn parse_string(args: &[Expr], idx: usize) -> LangResult<Rc<str>> {
+    match &args[idx] {
+        Expr::String(s) => Ok(s.clone()),
+        expr => Err(expected("a string", idx, expr)),
+    }
+}
+
+// Slices `string` by character indexes instead of byte offsets,
+// `end` defaults to the end of the string.
+pub fn slice_chars(string: &str, start: usize, end: Option<usize>) -> LangResult<&str> {
+    let offset = |idx: usize| {
+        string
+            .char_indices()
+            .map(|(offset, _)| offset)
+            .chain([string.len()])
+            .nth(idx)
+            .ok_or_else(|| LangError::Other(format!("index out of range: {idx}")))
+    };
+    let start_offset = offset(start)?;
+    let end_offset = match end {
+        Some(end) => offset(end)?,
+        None => string.len(),
+    };
+    match start_offset <= end_offset {
+        true => Ok(&string[start_offset..end_offset]),
+        false => Err(LangError::Other(format!(
+            "start index {start} is after the end index"
+        ))),
+    }
+}
+
+// A non-negative exact integer, like the index of `string-ref`.
+pub fn parse_index(expr: &Expr) -> LangResult<usize> {
+    match expr {
+        Expr::Num(Number::Int(n)) if *n >= 0 => Ok(*n as usize),
+        _ => Err(LangError::Other(format!(
+            "expected an index: {}",
+            Written(expr)
+        ))),
+    }
+}
+
+// The optional radix of `number->string` and `string->number`.
+pub fn parse_radix(radix: Option<&Expr>) -> LangResult<u32> {
+    match radix {
+        None => Ok(10),
+        Some(Expr::Num(Number::Int(radix @ (2 | 8 | 10 | 16)))) => Ok(*radix as u32),
+        Some(radix) => Err(LangError::Other(format!(
+            "expected a radix of 2, 8, 10 or 16: {}",
+            Written(radix)
+        ))),
+    }
+}
+
 // Every argument should be `Expr::Symbol`
-pub fn parse_list_of_args(args: Rc<Expr>) -> LangResult<Vec<String>> {
-    match args.as_ref() {
-        Expr::Symbol(s) => Ok(vec![s.clone()]),
-        Expr::SExpr(args) => args
+// The parameters of a lambda and its rest parameter:
+// `(x y)`, `(x y . rest)` or a single symbol `args` which takes every argument.
+// Like bindings, `nil` is an empty list of parameters.
+pub fn parse_list_of_args(args: &Expr) -> LangResult<(Vec<Symbol>, Option<Symbol>)> {
+    let param = |expr: &Expr| match expr {
+        Expr::Symbol(s) => Ok(*s),
+        _ => Err(LangError::Other(format!(
+            "expected a symbol as parameter, got {}",
+            Written(expr)
+        ))),
+    };
+    match args {
+        Expr::Nil => Ok((vec![], None)),
+        Expr::Symbol(s) => Ok((vec![], Some(*s))),
+        Expr::SExpr(args) => Ok((args.iter().map(param).collect::<LangResult<_>>()?, None)),
+        Expr::Pair(_) => {
+            let mut params = vec![];
+            let mut rest = args.clone();
+            while let Expr::Pair(pair) = rest {
+                params.push(param(&pair.car.borrow())?);
+                rest = pair.cdr.borrow().clone();
+            }
+            Ok((params, Some(param(&rest)?)))
+        }
+        _ => Err(LangError::Other(format!(
+            "expected a list of parameters, got {}",
+            Written(args)
+        ))),
+    }
+}
+
+// Every binding should be `(symbol expr)`, e.g. the bindings of `let`.
+// `nil` is the empty list too, so a printed expansion like `(let nil x)` reads back as code.
+pub fn parse_list_of_bindings(bindings: &Expr) -> LangResult<Vec<(Symbol, &Expr)>> {
+    match bindings {
+        Expr::Nil => Ok(vec![]),
+        Expr::SExpr(bindings) => bindings
             .iter()
-            .map(|expr| match expr {
-                Expr::Symbol(s) => Ok(s.clone()),
-                _ => Err(LangError::Other("expected a symbol".into())),
+            .map(|binding| match binding {
+                Expr::SExpr(binding) if binding.len() == 2 => {
+                    let symbol = *binding[0].inner_symbol()?;
+                    Ok((symbol, &binding[1]))
+                }
+                _ => Err(LangError::Other(
+                    "expected a binding like (name value)".into(),
+                )),
             })
             .collect(),
-        _ => Err(LangError::Unknown),
+        _ => Err(LangError::Other("expected a list of bindings".into())),
     }
 }
 
-pub fn child_env_for_lambda(
-    params: Rc<Expr>,
-    args: &[Expr],
-    parent_env: &mut Env,
-) -> LangResult<Env> {
-    let params = parse_list_of_args(params)?;
-    if params.len() != args.len() {
-        return Err(LangError::InvalidArgsLen);
-    }
-
-    let args = eval_args(args, parent_env)?;
-    let local = params.into_iter().zip(args);
-    let local = Map::from_iter(local);
-
-    let cache = parent_env.cache.clone();
-    let parent = Some(Rc::new(RefCell::new(parent_env.clone())));
-    let env = Env::new(local, parent, cache);
-    Ok(env)
+// Arguments are evaluated in the caller's environment,
+// but the new scope is a child of the lambda's captured environment.
+pub fn child_env_for_lambda(lambda: &Lambda, args: &[Expr], env: &mut Env) -> LangResult<Env> {
+    let args = args
+        .iter()
+        .map(|arg| eval(arg, env))
+        .collect::<LangResult<_>>()?;
+    lambda.call_env(args)
 }
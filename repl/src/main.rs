@@ -1,7 +1,10 @@
 mod config;
 
 use config::{CustomPrompt, LineEditorBuilder};
-use lang::{ast::Expr, env::Env};
+use lang::{
+    ast::{Expr, Written},
+    env::Env,
+};
 use reedline::{FileBackedHistory, Reedline, Signal};
 use std::fs;
 
@@ -54,15 +57,15 @@ fn main() {
                                     Ok(expr) => {
                                         let id = state.id;
                                         if state.debug_enabled {
-                                            println!("${id} = {expr}");
+                                            println!("${id} = {}", Written(&expr));
                                         } else if args[0] != ":l" {
-                                            println!("{expr}");
+                                            println!("{}", Written(&expr));
                                         } else if args[0] == ":l" {
                                             if let Expr::Symbol(ref symbol) = expr {
                                                 println!("{symbol}");
                                             }
                                         }
-                                        env.define(format!("${id}"), expr);
+                                        env.define(format!("${id}").into(), expr);
                                         state.id += 1;
                                     }
                                 }